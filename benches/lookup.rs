@@ -0,0 +1,81 @@
+use std::hint::black_box;
+
+use cachedb::{Blocking, CacheDb, KeyTraits};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+// Small keys are where hashing dominates the lookup costs.
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+struct Key(u64);
+
+impl KeyTraits for Key {}
+
+const KEYS: u64 = 10000;
+
+fn populated() -> CacheDb<Key, u64> {
+    let cdb = CacheDb::<Key, u64>::with_buckets(16);
+    cdb.disable_lru_eviction();
+    for key in 0..KEYS {
+        cdb.insert(&Key(key), |key| Ok(key.0)).unwrap();
+    }
+    cdb
+}
+
+fn lookup(c: &mut Criterion) {
+    let cdb = populated();
+
+    c.bench_function("get hit", |b| {
+        let mut key = 0;
+        b.iter(|| {
+            key = (key + 1) % KEYS;
+            *cdb.get(Blocking, black_box(&Key(key))).unwrap()
+        })
+    });
+
+    c.bench_function("get miss", |b| {
+        let mut key = 0;
+        b.iter(|| {
+            key = (key + 1) % KEYS;
+            cdb.get(Blocking, black_box(&Key(KEYS + key))).is_err()
+        })
+    });
+
+    c.bench_function("get_or_insert hit", |b| {
+        let mut key = 0;
+        b.iter(|| {
+            key = (key + 1) % KEYS;
+            *cdb.get_or_insert(Blocking, black_box(&Key(key)), |key| Ok(key.0))
+                .unwrap()
+        })
+    });
+
+    c.bench_function("contains_key &str", |b| {
+        let cdb = CacheDb::<String, u64>::with_buckets(16);
+        for key in 0..KEYS {
+            cdb.insert(&key.to_string(), |_| Ok(key)).unwrap();
+        }
+        let keys: Vec<String> = (0..KEYS).map(|key| key.to_string()).collect();
+        let mut key = 0;
+        b.iter(|| {
+            key = (key + 1) % KEYS as usize;
+            cdb.contains_key(black_box(keys[key].as_str()))
+        })
+    });
+}
+
+fn insert(c: &mut Criterion) {
+    c.bench_function("insert miss", |b| {
+        b.iter_batched(
+            || CacheDb::<Key, u64>::with_buckets(16),
+            |cdb| {
+                for key in 0..1000 {
+                    cdb.insert(black_box(&Key(key)), |key| Ok(key.0)).unwrap();
+                }
+                cdb
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, lookup, insert);
+criterion_main!(benches);
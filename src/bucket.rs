@@ -1,25 +1,61 @@
 #![allow(clippy::type_complexity)]
-use std::collections::{hash_map::DefaultHasher, HashSet};
-use std::hash::{Hash, Hasher};
+use std::borrow::Borrow;
+use std::collections::{btree_map, BTreeMap};
 use std::pin::Pin;
-use std::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 use std::fmt::{self, Debug, Formatter};
 use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
 
-use intrusive_collections::LinkedList;
 #[allow(unused_imports)]
 pub use log::{debug, error, info, trace, warn};
-use parking_lot::{Mutex, MutexGuard};
+use hashbrown::hash_table::{Entry as TableEntry, HashTable};
+use parking_lot::{Mutex, MutexGuard, RwLock};
 
-use crate::entry::EntryAdapter;
+use crate::entry::timestamp;
 use crate::Entry;
+use crate::EvictionPolicy;
 use crate::KeyTraits;
+use crate::BucketStats;
 use crate::UnsafeRef;
 
+/// Computes the weight of an entry.
+pub(crate) type Weigher<K, V> = dyn Fn(&K, &V) -> usize + Send + Sync;
+
+/// Gets called with the key and value of entries that are dropped from the CacheDb.
+pub(crate) type EvictionListener<K, V> = dyn Fn(K, V, EvictionCause) + Send + Sync;
+
+/// The reason why an entry was dropped from the CacheDb.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EvictionCause {
+    /// Evicted by the LRU or because the 'max_weight' was exceeded.
+    Evicted  = 1,
+    /// The time to live ended or the entry was idle for too long.
+    Expired  = 2,
+    /// Removed explicitly by the user.
+    Removed  = 3,
+    /// Overwritten by a new value.
+    Replaced = 4,
+}
+
+impl EvictionCause {
+    fn from_u8(cause: u8) -> Self {
+        match cause {
+            1 => EvictionCause::Evicted,
+            2 => EvictionCause::Expired,
+            3 => EvictionCause::Removed,
+            _ => EvictionCause::Replaced,
+        }
+    }
+}
+
 /// The internal representation of a Bucket.
 ///
-/// The LRU eviction is per bucket, this is most efficient and catches the corner cases where
-/// one bucket sees more entries than others.
+/// The eviction is per bucket, this is most efficient and catches the corner cases where
+/// one bucket sees more entries than others. Which entries are evicted is decided by the
+/// 'EvictionPolicy', the bucket decides how many.
 ///
 /// The eviction caclculation adapts itself based on the current capacity of the underlying
 /// hash map and some configuration variables. Every 'target_cooldown' inserts the
@@ -29,19 +65,46 @@ use crate::UnsafeRef;
 /// high cache ratio when memory requirements are modest and reduce the memory usage for
 /// caching at higher memory loads. When the cached entries exceed the 'cache_target' up to
 /// 'evict_batch' entries are removed from the cache.
-pub(crate) struct Bucket<K, V>
+///
+/// Entries that are idle for longer than 'time_to_idle' are taken from the policy by the time
+/// they were released and evicted.
+///
+/// Each entry has a weight, computed by the configured 'weigher' or 1 by default. When the sum
+/// of all weights exceeds 'max_weight' entries are evicted until the bucket is within its
+/// budget again.
+///
+/// Entries with a time to live are additionally kept in the 'ttl_queue' ordered by their
+/// expiration time. Expired entries are reclaimed from there independently of the
+/// 'cache_target'. Entries taken out of the map are removed from the 'ttl_queue' as well,
+/// thus it holds at most one item per entry.
+///
+/// The map is a raw hash table, entries store the hash of their key which was already
+/// computed for selecting the bucket. Thus keys are hashed only once per operation.
+pub(crate) struct Bucket<K, V, P>
 where
     K: KeyTraits,
+    P: EvictionPolicy<K, V>,
 {
-    map:      ManuallyDrop<Mutex<HashSet<Pin<Box<Entry<K, V>>>>>>,
-    lru_list: ManuallyDrop<Mutex<LinkedList<EntryAdapter<K, V>>>>,
+    map:                  ManuallyDrop<Mutex<HashTable<Pin<Box<Entry<K, V>>>>>>,
+    pub(crate) policy:    ManuallyDrop<Mutex<P>>,
+    pub(crate) ttl_queue: Mutex<BTreeMap<u64, Vec<(u64, K)>>>,
 
     // Stats section
-    pub(crate) cached: AtomicUsize,
+    pub(crate) cached:        AtomicUsize,
+    pub(crate) weight:        AtomicUsize, // protected by policy mutex
+    pub(crate) hits:          AtomicU64,
+    pub(crate) misses:        AtomicU64,
+    pub(crate) inserts:       AtomicU64,
+    pub(crate) ctor_failures: AtomicU64,
+    pub(crate) evictions:     AtomicU64,
+    pub(crate) expirations:   AtomicU64,
+    pub(crate) lock_timeouts: AtomicU64,
 
     // State section
     pub(crate) cache_target:     AtomicU8,
     pub(crate) target_countdown: AtomicU32,
+    // Counts 'CacheDb::disable_lru_eviction()' calls, the same in all buckets.
+    pub(crate) lru_disabled:     AtomicU32,
 
     // Configuration
     pub(crate) target_cooldown: AtomicU32,
@@ -51,73 +114,402 @@ where
     pub(crate) max_cache_percent:  AtomicU8,
     pub(crate) min_cache_percent:  AtomicU8,
 
-    pub(crate) evict_batch: AtomicU8,
+    pub(crate) evict_batch:  AtomicU8,
+    pub(crate) default_ttl:  AtomicU64,
+    pub(crate) time_to_idle: AtomicU64,
+    pub(crate) max_weight:   AtomicUsize,
+    pub(crate) weigher:      RwLock<Option<Arc<Weigher<K, V>>>>,
+    pub(crate) listener:     RwLock<Option<Arc<EvictionListener<K, V>>>>,
 }
 
-impl<K, V> Drop for Bucket<K, V>
+impl<K, V, P> Drop for Bucket<K, V, P>
 where
     K: KeyTraits,
+    P: EvictionPolicy<K, V>,
 {
     fn drop(&mut self) {
-        // The lru_list contains a number of pointers into map, which it walks and turns into
-        // references in its Drop impl. Therefore, we must ensure that lru_list is dropped before
-        // the map or we have a use-after-free.
+        // The policy contains a number of pointers into map, which it may walk and turn into
+        // references in its Drop impl. Therefore, we must ensure that the policy is dropped
+        // before the map or we have a use-after-free.
         unsafe {
-            ManuallyDrop::drop(&mut self.lru_list);
+            ManuallyDrop::drop(&mut self.policy);
             ManuallyDrop::drop(&mut self.map);
         }
     }
 }
 
-impl<K, V> Bucket<K, V>
+impl<K, V, P> Bucket<K, V, P>
 where
     K: KeyTraits,
+    P: EvictionPolicy<K, V>,
 {
     pub(crate) fn new() -> Self {
         Self {
-            map:                ManuallyDrop::new(Mutex::new(HashSet::new())),
-            lru_list:           ManuallyDrop::new(Mutex::new(LinkedList::new(EntryAdapter::new()))),
+            map:                ManuallyDrop::new(Mutex::new(HashTable::new())),
+            policy:             ManuallyDrop::new(Mutex::new(P::default())),
+            ttl_queue:          Mutex::new(BTreeMap::new()),
             cached:             AtomicUsize::new(0),
+            weight:             AtomicUsize::new(0),
+            hits:               AtomicU64::new(0),
+            misses:             AtomicU64::new(0),
+            inserts:            AtomicU64::new(0),
+            ctor_failures:      AtomicU64::new(0),
+            evictions:          AtomicU64::new(0),
+            expirations:        AtomicU64::new(0),
+            lock_timeouts:      AtomicU64::new(0),
             cache_target:       AtomicU8::new(50),
             target_countdown:   AtomicU32::new(0),
+            lru_disabled:       AtomicU32::new(0),
             target_cooldown:    AtomicU32::new(100),
             max_capacity_limit: AtomicUsize::new(10000000),
             min_capacity_limit: AtomicUsize::new(1000),
             max_cache_percent:  AtomicU8::new(60),
             min_cache_percent:  AtomicU8::new(5),
             evict_batch:        AtomicU8::new(16),
+            default_ttl:        AtomicU64::new(u64::MAX),
+            time_to_idle:       AtomicU64::new(u64::MAX),
+            max_weight:         AtomicUsize::new(usize::MAX),
+            weigher:            RwLock::new(None),
+            listener:           RwLock::new(None),
+        }
+    }
+
+    pub(crate) fn lock_map(&self) -> MapLock<'_, K, V, P> {
+        MapLock {
+            bucket:  self,
+            guard:   ManuallyDrop::new(self.map.lock()),
+            evicted: Vec::new(),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> BucketStats {
+        BucketStats {
+            hits:          self.hits.load(Ordering::Relaxed),
+            misses:        self.misses.load(Ordering::Relaxed),
+            inserts:       self.inserts.load(Ordering::Relaxed),
+            ctor_failures: self.ctor_failures.load(Ordering::Relaxed),
+            evictions:     self.evictions.load(Ordering::Relaxed),
+            expirations:   self.expirations.load(Ordering::Relaxed),
+            lock_timeouts: self.lock_timeouts.load(Ordering::Relaxed),
+            len:           self.lock_map().len(),
+            cached:        self.cached.load(Ordering::Relaxed),
+            cache_target:  self.cache_target.load(Ordering::Relaxed),
         }
     }
 
-    pub(crate) fn lock_map(&self) -> MutexGuard<HashSet<Pin<Box<Entry<K, V>>>>> {
-        self.map.lock()
+    /// Counts removed entries by their cause.
+    fn count_removal(&self, cause: EvictionCause) {
+        let counter = match cause {
+            EvictionCause::Evicted => &self.evictions,
+            EvictionCause::Expired => &self.expirations,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns true when the time to live of an entry ended or it was unused for longer than
+    /// 'time_to_idle'.
+    fn is_stale(&self, entry: &Entry<K, V>) -> bool {
+        if entry.is_expired() {
+            return true;
+        }
+        let time_to_idle = self.time_to_idle.load(Ordering::Relaxed);
+        time_to_idle != u64::MAX
+            && entry.use_count.load(Ordering::Relaxed) == 0
+            && entry
+                .released_at
+                .load(Ordering::Relaxed)
+                .saturating_add(time_to_idle)
+                <= timestamp()
+    }
+
+    /// Looks up the entry for 'key'. Entries whose time to live ended or which are idle for
+    /// too long are treated as missing and become removed.
+    pub(crate) fn get_entry<'m, Q>(
+        &self,
+        map_lock: &'m mut MapLock<'_, K, V, P>,
+        hash: u64,
+        key: &Q,
+    ) -> Option<&'m Entry<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let entry: *const Entry<K, V> = &**map_lock.get(hash, key)?;
+        // Safety: the entry is owned by the map which we have locked
+        if self.is_stale(unsafe { &*entry }) {
+            map_lock.remove(hash, key, EvictionCause::Expired);
+            None
+        } else {
+            Some(unsafe { &*entry })
+        }
     }
 
     pub(crate) fn use_entry(&self, entry: &Entry<K, V>) {
-        let mut lru_lock = self.lru_list.lock();
-        if entry.lru_link.is_linked() {
-            unsafe { lru_lock.cursor_mut_from_ptr(&*entry).remove() };
+        let mut policy = self.policy.lock();
+        let cached = entry.in_policy.swap(false, Ordering::Relaxed);
+        if cached {
             self.cached.fetch_sub(1, Ordering::Relaxed);
         }
+        policy.use_entry(entry, cached);
         entry.use_count.fetch_add(1, Ordering::Relaxed);
         entry.expire.store(false, Ordering::Relaxed);
     }
 
-    pub(crate) fn unuse_entry(&self, entry: &Entry<K, V>) {
-        let mut lru_lock = self.lru_list.lock();
+    /// Takes another use of an entry that is already in use. Such entries are not owned by
+    /// the policy, thus neither the map nor the policy needs to be locked.
+    pub(crate) fn reuse_entry(&self, entry: &Entry<K, V>) {
+        debug_assert!(entry.use_count.load(Ordering::Relaxed) > 0);
+        entry.use_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Takes an entry in use without telling the policy, thus the use does not count as an
+    /// access. Unused entries stay with the policy, when one is selected for eviction while
+    /// in use it is taken back but not evicted. Must be called with the map locked.
+    pub(crate) fn peek_entry(&self, _map_lock: &MapLock<'_, K, V, P>, entry: &Entry<K, V>) {
+        entry.use_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Releases an entry. When this was the last user the entry is passed to the policy or
+    /// dropped when it got removed in the meantime. Entries taken by 'peek_entry()' which
+    /// are still owned by the policy are left there.
+    ///
+    /// # Safety
+    ///
+    /// The entry must be in use and the caller must not access it afterwards.
+    pub(crate) unsafe fn unuse_entry(&self, entry: *const Entry<K, V>) {
+        // Only the last user hands the entry to the policy, others don't need to lock it.
+        let use_count = &(*entry).use_count;
+        let mut count = use_count.load(Ordering::Relaxed);
+        while count > 1 {
+            match use_count.compare_exchange_weak(
+                count,
+                count - 1,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(current) => count = current,
+            }
+        }
+
+        let mut policy = self.policy.lock();
 
-        if entry.use_count.fetch_sub(1, Ordering::Relaxed) == 1 {
+        // Another user may have taken the entry in the meantime.
+        if use_count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            let removed = (*entry).removed.load(Ordering::Relaxed);
+            if removed != 0 {
+                drop(policy);
+                // was leaked by detach_entry()
+                self.notify(
+                    Box::into_pin(Box::from_raw(entry as *mut Entry<K, V>)),
+                    EvictionCause::from_u8(removed),
+                );
+                return;
+            }
+            if (*entry).in_policy.load(Ordering::Relaxed) {
+                return;
+            }
+
+            (*entry).released_at.store(timestamp(), Ordering::Relaxed);
+            (*entry).in_policy.store(true, Ordering::Relaxed);
             self.cached.fetch_add(1, Ordering::Relaxed);
-            if !entry.expire.load(Ordering::Relaxed) {
-                lru_lock.push_back(unsafe { UnsafeRef::from_raw(entry) });
-            } else {
-                lru_lock.push_front(unsafe { UnsafeRef::from_raw(entry) });
+            policy.release(UnsafeRef::from_raw(entry));
+            if (*entry).expire.load(Ordering::Relaxed) {
+                policy.expire(&*entry);
+            }
+        }
+    }
+
+    /// Marks an entry for expiration. Unused entries are expired by the policy immediately,
+    /// entries in use when they become released.
+    pub(crate) fn expire_entry(&self, entry: &Entry<K, V>) {
+        let mut policy = self.policy.lock();
+        entry.expire.store(true, Ordering::Relaxed);
+        if entry.in_policy.load(Ordering::Relaxed) {
+            policy.expire(entry);
+        }
+    }
+
+    /// Takes care of an entry that was taken out of the map. When the entry is not in use it
+    /// is taken back from the policy and returned, the caller should drop it after releasing
+    /// the map lock. Entries in use are marked as removed and dropped by their last user.
+    fn detach_entry(
+        &self,
+        entry: Pin<Box<Entry<K, V>>>,
+        cause: EvictionCause,
+    ) -> Option<Pin<Box<Entry<K, V>>>> {
+        self.unschedule_expire(&entry);
+        let mut policy = self.policy.lock();
+        self.weight
+            .fetch_sub(entry.weight.load(Ordering::Relaxed), Ordering::Relaxed);
+
+        if entry.in_policy.swap(false, Ordering::Relaxed) {
+            policy.remove(&entry);
+            self.cached.fetch_sub(1, Ordering::Relaxed);
+        }
+        if entry.use_count.load(Ordering::Relaxed) == 0 {
+            Some(entry)
+        } else {
+            entry.removed.store(cause as u8, Ordering::Relaxed);
+            // Leak it, unuse_entry() will drop it
+            let _ = Box::into_raw(unsafe { Pin::into_inner_unchecked(entry) });
+            None
+        }
+    }
+
+    /// Collects the keys of all entries which are not stale.
+    pub(crate) fn keys(&self) -> Vec<K> {
+        self.lock_map()
+            .iter()
+            .filter(|entry| !self.is_stale(entry))
+            .map(|entry| entry.key.clone())
+            .collect()
+    }
+
+    /// Collects the hashes and addresses of all entries, these are only used to find the
+    /// entries again by 'peek_stored()'.
+    pub(crate) fn entry_ptrs(&self) -> Vec<(u64, *const Entry<K, V>)> {
+        self.lock_map()
+            .iter()
+            .map(|entry| (entry.hash, &**entry as *const Entry<K, V>))
+            .collect()
+    }
+
+    /// Takes the entry at 'entry_ptr' in use by 'peek_entry()' when it is still stored in
+    /// the map and not stale.
+    pub(crate) fn peek_stored(
+        &self,
+        hash: u64,
+        entry_ptr: *const Entry<K, V>,
+    ) -> Option<&Entry<K, V>> {
+        let map_lock = self.lock_map();
+        let entry: *const Entry<K, V> =
+            &**map_lock.find(hash, |stored| std::ptr::eq(&**stored, entry_ptr))?;
+        // Safety: the entry is owned by the map which we have locked
+        let entry = unsafe { &*entry };
+        if self.is_stale(entry) {
+            return None;
+        }
+        self.peek_entry(&map_lock, entry);
+        Some(entry)
+    }
+
+    /// Removes the given entry from the map, when it is still stored there.
+    pub(crate) fn remove_entry(&self, entry: &Entry<K, V>, cause: EvictionCause) {
+        let mut map_lock = self.lock_map();
+        if let Ok(stored) = map_lock
+            .guard
+            .find_entry(entry.hash, |stored| std::ptr::eq(&**stored, entry))
+        {
+            let (stored, _) = stored.remove();
+            map_lock.detach(stored, cause);
+        }
+    }
+
+    /// Passes the key and value of a dropped entry to the eviction listener.
+    fn notify(&self, entry: Pin<Box<Entry<K, V>>>, cause: EvictionCause) {
+        let listener = self.listener.read().clone();
+        if let Some(listener) = listener {
+            if let (key, Some(value)) = Entry::into_parts(entry) {
+                listener(key, value, cause);
+            }
+        }
+    }
+
+    /// Passes the key and the overwritten value of an entry to the eviction listener.
+    pub(crate) fn notify_replaced(&self, key: &K, value: V) {
+        let listener = self.listener.read().clone();
+        if let Some(listener) = listener {
+            listener(key.clone(), value, EvictionCause::Replaced);
+        }
+    }
+
+    /// Sets the expiration time of an entry and registers it in the 'ttl_queue'.
+    pub(crate) fn schedule_expire(&self, entry: &Entry<K, V>, expires_at: u64) {
+        let mut ttl_queue = self.ttl_queue.lock();
+        entry.expires_at.store(expires_at, Ordering::Relaxed);
+        ttl_queue
+            .entry(expires_at)
+            .or_default()
+            .push((entry.hash, entry.key.clone()));
+    }
+
+    /// Removes an entry from the 'ttl_queue'. Nothing to do when it has no time to live or
+    /// was already taken from the 'ttl_queue' by 'reclaim_expired()'.
+    fn unschedule_expire(&self, entry: &Entry<K, V>) {
+        let mut ttl_queue = self.ttl_queue.lock();
+        let expires_at = entry.expires_at.load(Ordering::Relaxed);
+        if let btree_map::Entry::Occupied(mut scheduled) = ttl_queue.entry(expires_at) {
+            let items = scheduled.get_mut();
+            if let Some(index) = items
+                .iter()
+                .position(|(hash, key)| *hash == entry.hash && *key == entry.key)
+            {
+                items.swap_remove(index);
+            }
+            if items.is_empty() {
+                scheduled.remove();
+            }
+        }
+    }
+
+    /// Evicts all entries that are idle for longer than 'time_to_idle' and up to
+    /// 'evict_batch' entries whose time to live ended. Expired entries in use are removed
+    /// from the map and dropped when released.
+    pub(crate) fn reclaim_expired(&self, map_lock: &mut MapLock<'_, K, V, P>) {
+        let now = timestamp();
+
+        let time_to_idle = self.time_to_idle.load(Ordering::Relaxed);
+        if time_to_idle != u64::MAX {
+            let mut policy = self.policy.lock();
+            let idle = |entry: &Entry<K, V>| {
+                entry
+                    .released_at
+                    .load(Ordering::Relaxed)
+                    .saturating_add(time_to_idle)
+                    <= now
+            };
+            let scan = self.evict_batch.load(Ordering::Relaxed) as usize;
+            while let Some(entry) = policy.idle(&idle, scan) {
+                if self.account_unused(&entry) {
+                    map_lock.remove_unused(&entry, EvictionCause::Expired);
+                }
+            }
+        }
+
+        for _ in 0..self.evict_batch.load(Ordering::Relaxed) {
+            // The 'ttl_queue' must be unlocked when removing, detaching entries locks it.
+            let (hash, key) = {
+                let mut ttl_queue = self.ttl_queue.lock();
+                let mut first = match ttl_queue.first_entry() {
+                    Some(first) if *first.key() <= now => first,
+                    _ => break,
+                };
+                let item = first.get_mut().pop().unwrap();
+                if first.get().is_empty() {
+                    first.remove();
+                }
+                item
+            };
+
+            // The entry may have been replaced meanwhile, check its actual expiration time.
+            if matches!(map_lock.get(hash, &key), Some(entry) if entry.is_expired()) {
+                map_lock.remove(hash, &key, EvictionCause::Expired);
             }
         }
     }
 
-    /// recalculates the 'cache_target' and evicts entries from the LRU when above target
-    pub(crate) fn maybe_evict(&self, map_lock: &mut MutexGuard<HashSet<Pin<Box<Entry<K, V>>>>>) {
+    /// Returns true while the eviction is disabled by 'CacheDb::disable_lru_eviction()'.
+    pub(crate) fn eviction_disabled(&self) -> bool {
+        self.lru_disabled.load(Ordering::Relaxed) != 0
+    }
+
+    /// recalculates the 'cache_target' and evicts entries when above target
+    pub(crate) fn maybe_evict(&self, map_lock: &mut MapLock<'_, K, V, P>) {
+        if self.eviction_disabled() {
+            return;
+        }
         let min_capacity_limit = self.min_capacity_limit.load(Ordering::Relaxed);
         let max_capacity_limit = self.max_capacity_limit.load(Ordering::Relaxed);
         let max_cache_percent = self.max_cache_percent.load(Ordering::Relaxed);
@@ -150,11 +542,7 @@ where
         }
 
         let cached = self.cached.load(Ordering::Relaxed);
-        let percent_cached = if capacity > 0 {
-            (cached * 100 / capacity) as u8
-        } else {
-            0
-        };
+        let percent_cached = (cached * 100).checked_div(capacity).unwrap_or(0) as u8;
 
         if capacity > min_capacity_limit
             && percent_cached > self.cache_target.load(Ordering::Relaxed)
@@ -162,32 +550,234 @@ where
             // lets evict some entries
             self.evict(self.evict_batch.load(Ordering::Relaxed) as usize, map_lock);
         }
+
+        self.evict_overweight(map_lock);
     }
 
-    /// evicts up to 'n' entries from the LRU list. Returns the number of evicted entries which
-    /// may be less than 'n' in case the list got depleted.
-    pub(crate) fn evict(
-        &self,
-        n: usize,
-        map_lock: &mut MutexGuard<HashSet<Pin<Box<Entry<K, V>>>>>,
-    ) -> usize {
+    /// evicts up to 'n' entries selected by the policy. Returns the number of evicted entries
+    /// which may be less than 'n' in case there are no more unused entries.
+    pub(crate) fn evict(&self, n: usize, map_lock: &mut MapLock<'_, K, V, P>) -> usize {
         #[cfg(feature = "logging")]
         debug!("evicting {} elements", n);
         for i in 0..n {
-            if let Some(entry) = self.lru_list.lock().pop_front() {
-                map_lock.remove(&entry.key);
-                self.cached.fetch_sub(1, Ordering::Relaxed);
+            if let Some(entry) = self.pop_victim(&mut self.policy.lock()) {
+                map_lock.remove_unused(&entry, EvictionCause::Evicted);
             } else {
                 return i;
             }
         }
         n
     }
+
+    /// Evicts entries selected by the policy until the bucket is within its 'max_weight'.
+    /// Returns the number of evicted entries.
+    pub(crate) fn evict_overweight(&self, map_lock: &mut MapLock<'_, K, V, P>) -> usize {
+        if self.eviction_disabled() {
+            return 0;
+        }
+        let max_weight = self.max_weight.load(Ordering::Relaxed);
+        let mut evicted = 0;
+        let mut policy = self.policy.lock();
+        while self.weight.load(Ordering::Relaxed) > max_weight {
+            if let Some(entry) = self.pop_victim(&mut policy) {
+                map_lock.remove_unused(&entry, EvictionCause::Evicted);
+                evicted += 1;
+            } else {
+                break;
+            }
+        }
+        evicted
+    }
+
+    /// Takes the next victim from the policy, the caller has to remove it from the map.
+    fn pop_victim(&self, policy: &mut MutexGuard<P>) -> Option<UnsafeRef<Entry<K, V>>> {
+        loop {
+            let entry = policy.victim()?;
+            if self.account_unused(&entry) {
+                policy.evicted(&entry);
+                return Some(entry);
+            }
+        }
+    }
+
+    /// Removes an entry that was taken from the policy from the stats. Returns false when the
+    /// entry is in use by 'peek_entry()', it must not be evicted then and is passed to the
+    /// policy again when released.
+    fn account_unused(&self, entry: &Entry<K, V>) -> bool {
+        self.cached.fetch_sub(1, Ordering::Relaxed);
+        entry.in_policy.store(false, Ordering::Relaxed);
+        if entry.use_count.load(Ordering::Relaxed) != 0 {
+            return false;
+        }
+        self.weight
+            .fetch_sub(entry.weight.load(Ordering::Relaxed), Ordering::Relaxed);
+        true
+    }
+
+    /// Computes the weight of an entry and accounts it in the bucket. Without a 'weigher'
+    /// new entries weigh 1 and the weight of existing entries is left alone. Returns true when
+    /// the bucket exceeds its 'max_weight' afterwards.
+    pub(crate) fn update_weight(&self, entry: &Entry<K, V>, value: &V, new: bool) -> bool {
+        let weigher = self.weigher.read().clone();
+        let weight = match weigher {
+            Some(weigher) => weigher(&entry.key, value),
+            None if new => 1,
+            None => return false,
+        };
+
+        let _policy = self.policy.lock();
+        // removed entries are already subtracted
+        if entry.removed.load(Ordering::Relaxed) == 0 {
+            let old = entry.weight.swap(weight, Ordering::Relaxed);
+            self.weight.fetch_add(weight, Ordering::Relaxed);
+            self.weight.fetch_sub(old, Ordering::Relaxed);
+        }
+        self.weight.load(Ordering::Relaxed) > self.max_weight.load(Ordering::Relaxed)
+    }
+}
+
+/// Lock on the map of a bucket. Entries removed while the lock is held are collected and passed
+/// to the eviction listener after the lock is released.
+pub(crate) struct MapLock<'a, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    bucket:  &'a Bucket<K, V, P>,
+    guard:   ManuallyDrop<MutexGuard<'a, HashTable<Pin<Box<Entry<K, V>>>>>>,
+    evicted: Vec<(Pin<Box<Entry<K, V>>>, EvictionCause)>,
+}
+
+impl<K, V, P> MapLock<'_, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    /// Looks up the entry for 'key', which may be any form the keys can be borrowed as.
+    /// 'hash' must be the hash of 'key'.
+    pub(crate) fn get<Q>(&self, hash: u64, key: &Q) -> Option<&Pin<Box<Entry<K, V>>>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.guard.find(hash, |entry| {
+            entry.hash == hash && entry.key.borrow() == key
+        })
+    }
+
+    /// Looks up the entry for 'key' and inserts a new one when there is none, probing the map
+    /// only once. Stale entries are replaced by the new entry. Returns 'Ok' with the existing
+    /// entry or 'Err' with the new entry, which is in use by the caller.
+    pub(crate) fn get_or_insert(
+        &mut self,
+        hash: u64,
+        key: &K,
+    ) -> Result<*const Entry<K, V>, *const Entry<K, V>> {
+        let bucket = self.bucket;
+        let found = self.guard.entry(
+            hash,
+            |entry| entry.hash == hash && entry.key == *key,
+            |entry| entry.hash,
+        );
+        let vacant = match found {
+            TableEntry::Occupied(occupied) if bucket.is_stale(occupied.get()) => {
+                let (entry, vacant) = occupied.remove();
+                bucket.count_removal(EvictionCause::Expired);
+                if let Some(entry) = bucket.detach_entry(entry, EvictionCause::Expired) {
+                    self.evicted.push((entry, EvictionCause::Expired));
+                }
+                vacant
+            }
+            TableEntry::Occupied(occupied) => return Ok(&**occupied.get()),
+            TableEntry::Vacant(vacant) => vacant,
+        };
+
+        let entry = Box::pin(Entry::new(key.clone(), hash));
+        let entry_ptr: *const Entry<K, V> = &*entry;
+        bucket.policy.lock().insert(&entry);
+        vacant.insert(entry);
+        Err(entry_ptr)
+    }
+
+    /// Removes the entry for 'key' from the map. Returns false when there was no entry.
+    /// Entries in use are dropped by their last user.
+    pub(crate) fn remove<Q>(&mut self, hash: u64, key: &Q, cause: EvictionCause) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        if let Ok(entry) = self.guard.find_entry(hash, |entry| {
+            entry.hash == hash && entry.key.borrow() == key
+        }) {
+            let (entry, _) = entry.remove();
+            self.detach(entry, cause);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Accounts an entry that was taken out of the map.
+    fn detach(&mut self, entry: Pin<Box<Entry<K, V>>>, cause: EvictionCause) {
+        self.bucket.count_removal(cause);
+        if let Some(entry) = self.bucket.detach_entry(entry, cause) {
+            self.evicted.push((entry, cause));
+        }
+    }
+
+    /// Removes an entry that was already taken back from the policy.
+    fn remove_unused(&mut self, entry: &Entry<K, V>, cause: EvictionCause) {
+        let (entry, _) = self
+            .guard
+            .find_entry(entry.hash, |stored| std::ptr::eq(&**stored, entry))
+            .ok()
+            .unwrap()
+            .remove();
+        self.bucket.unschedule_expire(&entry);
+        self.bucket.count_removal(cause);
+        self.evicted.push((entry, cause));
+    }
 }
 
-impl<K, V> Debug for Bucket<K, V>
+impl<K, V, P> Deref for MapLock<'_, K, V, P>
 where
     K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    type Target = HashTable<Pin<Box<Entry<K, V>>>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<K, V, P> DerefMut for MapLock<'_, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<K, V, P> Drop for MapLock<'_, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        for (entry, cause) in self.evicted.drain(..) {
+            self.bucket.notify(entry, cause);
+        }
+    }
+}
+
+impl<K, V, P> Debug for Bucket<K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let map_lock = self.lock_map();
@@ -195,7 +785,9 @@ where
             .field("map.len()", &map_lock.len())
             .field("map.capacity()", &map_lock.capacity())
             .field("cached", &self.cached.load(Ordering::Relaxed))
+            .field("weight", &self.weight.load(Ordering::Relaxed))
             .field("cache_target", &self.cache_target.load(Ordering::Relaxed))
+            .field("lru_disabled", &self.lru_disabled.load(Ordering::Relaxed))
             .field(
                 "max_capacity_limit",
                 &self.max_capacity_limit.load(Ordering::Relaxed),
@@ -213,19 +805,9 @@ where
                 &self.min_cache_percent.load(Ordering::Relaxed),
             )
             .field("evict_batch", &self.evict_batch.load(Ordering::Relaxed))
+            .field("default_ttl", &self.default_ttl.load(Ordering::Relaxed))
+            .field("time_to_idle", &self.time_to_idle.load(Ordering::Relaxed))
+            .field("max_weight", &self.max_weight.load(Ordering::Relaxed))
             .finish()
     }
 }
-
-/// Defines into which bucket a key falls. The default implementation uses the Hash trait for
-/// this. Custom implementations can override this to something more simple. It is recommended
-/// to implement this because very good distribution of the resulting value is not as
-/// important as for the hashmap.
-pub trait Bucketize: Hash {
-    // Must return an value 0..N-1 otherwise CacheDb will panic with array access out of bounds.
-    fn bucket<const N: usize>(&self) -> usize {
-        let mut hasher = DefaultHasher::new();
-        self.hash(&mut hasher);
-        hasher.finish() as usize % N
-    }
-}
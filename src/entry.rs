@@ -1,53 +1,188 @@
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::OnceLock;
 #[cfg(feature = "logging")]
 use std::fmt::Debug;
 use std::marker::PhantomPinned;
+use std::mem::ManuallyDrop;
 use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
-use std::hash::{Hash, Hasher};
-use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::Arc;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::time::{Duration, Instant};
 
 use intrusive_collections::{intrusive_adapter, LinkedListLink, UnsafeRef};
-use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use parking_lot::{RwLock, RwLockReadGuard, RwLockUpgradableReadGuard, RwLockWriteGuard};
 
-use crate::{bucket::Bucket, Bucketize};
+#[cfg(feature = "async")]
+use crate::AsyncLockingMethod;
+use crate::{
+    bucket::Bucket, CacheDb, DynResult, Error, EvictionCause, EvictionPolicy, LockingMethod, Lru,
+};
 
 /// Collects the traits a Key must implement, any user defined Key type must implement this
 /// trait and any traits it derives from.
 /// The 'Debug' trait is only required when the feature 'logging' is enabled.
 #[cfg(not(feature = "logging"))]
-pub trait KeyTraits: Eq + Clone + Bucketize {}
+pub trait KeyTraits: Eq + Clone + Hash {}
 #[cfg(feature = "logging")]
-pub trait KeyTraits: Eq + Clone + Bucketize + Debug {}
+pub trait KeyTraits: Eq + Clone + Hash + Debug {}
+
+impl KeyTraits for String {}
+
+/// Returns a monotonic timestamp in nanoseconds. Entries store their points in time in this
+/// format to be able to keep them in atomics.
+pub(crate) fn timestamp() -> u64 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as u64
+}
+
+/// Converts a Duration into nanoseconds, saturating at 'u64::MAX' which stands for 'never'.
+pub(crate) fn nanos(duration: Option<Duration>) -> u64 {
+    duration.map_or(u64::MAX, |d| d.as_nanos().min(u64::MAX as u128) as u64)
+}
 
 /// User data is stored behind RwLocks in an entry. Furthermore some management information
 /// like the LRU list node are stored here. Entries have stable addresses and can't be moved
-/// in memory.
-pub(crate) struct Entry<K, V> {
-    pub(crate) key:       K,
+/// in memory. Only 'EvictionPolicy' implementations get to see entries.
+pub struct Entry<K, V> {
+    pub(crate) key:         K,
+    // Computed once by the hasher of the CacheDb, the map never rehashes keys.
+    pub(crate) hash:        u64,
     // The Option is only used for delaying the construction with write lock held.
-    pub(crate) value:     RwLock<Option<V>>,
-    pub(crate) lru_link:  LinkedListLink, // protected by lru_list mutex
-    pub(crate) use_count: AtomicUsize,
-    pub(crate) expire:    AtomicBool,
-    _pin:                 PhantomPinned,
+    pub(crate) value:       RwLock<Option<V>>,
+    pub(crate) lru_link:    LinkedListLink, // protected by policy mutex
+    pub(crate) segment:     AtomicU8,       // protected by policy mutex
+    pub(crate) use_count:   AtomicUsize,
+    // Set while the entry is owned by the policy.
+    pub(crate) in_policy:   AtomicBool, // protected by policy mutex
+    pub(crate) expire:      AtomicBool,
+    // The 'EvictionCause' when the entry was removed from the map while in use, the last user
+    // drops it then. Zero while the entry is stored in the map.
+    pub(crate) removed:     AtomicU8,
+    // Timestamp when the time to live ends, 'u64::MAX' when the entry does not expire.
+    pub(crate) expires_at:  AtomicU64,
+    // Timestamp when the entry was released by its last user.
+    pub(crate) released_at: AtomicU64,
+    pub(crate) weight:      AtomicUsize, // protected by policy mutex
+    // Tasks waiting for the value lock, notified whenever a lock is released.
+    #[cfg(feature = "async")]
+    pub(crate) unlocked:    tokio::sync::Notify,
+    _pin:                   PhantomPinned,
 }
 
-intrusive_adapter!(pub(crate) EntryAdapter<K, V> = UnsafeRef<Entry<K, V>>: Entry<K, V> { lru_link: LinkedListLink });
+// The 'lru_link' is only accessed while the 'policy' mutex of the hosting bucket is held,
+// everything else is either immutable, atomic or protected by the RwLock.
+unsafe impl<K: Sync, V: Send + Sync> Sync for Entry<K, V> {}
+
+intrusive_adapter!(
+    /// Links entries into the intrusive lists of an 'EvictionPolicy'.
+    pub EntryAdapter<K, V> = UnsafeRef<Entry<K, V>>: Entry<K, V> { lru_link: LinkedListLink }
+);
 
 impl<K: KeyTraits, V> Entry<K, V> {
-    pub(crate) fn new(key: K) -> Self {
+    pub(crate) fn new(key: K, hash: u64) -> Self {
         Entry {
             key,
+            hash,
             value: RwLock::new(None),
             lru_link: LinkedListLink::new(),
+            segment: AtomicU8::new(0),
             use_count: AtomicUsize::new(1),
+            in_policy: AtomicBool::new(false),
             expire: AtomicBool::new(false),
+            removed: AtomicU8::new(0),
+            expires_at: AtomicU64::new(u64::MAX),
+            released_at: AtomicU64::new(0),
+            weight: AtomicUsize::new(0),
+            #[cfg(feature = "async")]
+            unlocked: tokio::sync::Notify::new(),
             _pin: PhantomPinned,
         }
     }
 }
 
+impl<K, V> Entry<K, V> {
+    /// Returns the key of this entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Returns the hash of the key as computed by the hasher of the CacheDb. Policies should
+    /// use this instead of hashing the key again.
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    /// Returns the policy specific segment of this entry, zero for new entries.
+    pub fn segment(&self) -> u8 {
+        self.segment.load(Ordering::Relaxed)
+    }
+
+    /// Sets the policy specific segment of this entry, for example which list it is linked
+    /// into.
+    pub fn set_segment(&self, segment: u8) {
+        self.segment.store(segment, Ordering::Relaxed);
+    }
+
+    /// Destructures an entry that is not referenced anymore.
+    pub(crate) fn into_parts(entry: Pin<Box<Self>>) -> (K, Option<V>) {
+        // Safety: the entry is dropped here, nothing refers to it anymore
+        let entry = unsafe { Pin::into_inner_unchecked(entry) };
+        (entry.key, entry.value.into_inner())
+    }
+
+    /// Wakes the tasks waiting for the value lock, must be called whenever a lock got
+    /// released.
+    #[inline]
+    pub(crate) fn notify_unlocked(&self) {
+        #[cfg(feature = "async")]
+        self.unlocked.notify_waiters();
+    }
+
+    /// Obtains a lock on the value by 'try_lock' without blocking the thread. Waits until
+    /// the 'deadline' for other locks to be released.
+    #[cfg(feature = "async")]
+    pub(crate) async fn lock_async<'a, G>(
+        &'a self,
+        deadline: Option<Instant>,
+        try_lock: impl Fn(&'a RwLock<Option<V>>) -> Option<G>,
+    ) -> Result<G, Error> {
+        if let Some(guard) = try_lock(&self.value) {
+            return Ok(guard);
+        }
+
+        let wait = async {
+            loop {
+                let notified = self.unlocked.notified();
+                tokio::pin!(notified);
+                // register before trying, otherwise a release in between would be missed
+                notified.as_mut().enable();
+                if let Some(guard) = try_lock(&self.value) {
+                    return guard;
+                }
+                notified.await;
+            }
+        };
+
+        match deadline {
+            None => Ok(wait.await),
+            Some(deadline) if deadline <= Instant::now() => Err(Error::LockUnavailable),
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), wait)
+                .await
+                .map_err(|_| Error::LockUnavailable),
+        }
+    }
+
+    /// Returns true when the time to live of this entry has ended.
+    pub(crate) fn is_expired(&self) -> bool {
+        let expires_at = self.expires_at.load(Ordering::Relaxed);
+        expires_at != u64::MAX && expires_at <= timestamp()
+    }
+}
+
 // Hashes only over the key part.
 impl<K: KeyTraits, V> Hash for Entry<K, V> {
     fn hash<H: Hasher>(&self, state: &mut H) {
@@ -64,50 +199,317 @@ impl<K: PartialEq, V> PartialEq for Entry<K, V> {
 
 impl<K: PartialEq, V> Eq for Entry<K, V> {}
 
-// We need this to be able to lookup a Key in a HashSet containing pinboxed entries.
-impl<K, V> Borrow<K> for Pin<Box<Entry<K, V>>>
+/// Write lock on a freshly inserted entry while its value gets constructed.  When this is
+/// dropped before the value is set (because the constructor failed or panicked) the entry is
+/// removed from the map again. Threads that are waiting for the lock will then find it empty.
+pub(crate) struct Placeholder<'a, K, V, P>
 where
     K: KeyTraits,
+    P: EvictionPolicy<K, V>,
 {
-    fn borrow(&self) -> &K {
-        &self.key
+    bucket:       &'a Bucket<K, V, P>,
+    entry:        &'a Entry<K, V>,
+    guard:        ManuallyDrop<RwLockWriteGuard<'a, Option<V>>>,
+    ttl:          u64,
+    // Set while a constructor runs, dropping the placeholder then counts as a failure.
+    constructing: bool,
+}
+
+impl<'a, K, V, P> Placeholder<'a, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    /// Locks a new entry. Must be called before the map lock is released, then this will
+    /// never block because no other thread can know about this entry yet. 'ttl' is the time
+    /// to live in nanoseconds, 'u64::MAX' for entries that do not expire.
+    pub(crate) fn new(bucket: &'a Bucket<K, V, P>, entry: *const Entry<K, V>, ttl: u64) -> Self {
+        // Safety: the entry is in use and thus can not be dropped
+        let entry = unsafe { &*entry };
+        Placeholder {
+            bucket,
+            entry,
+            guard: ManuallyDrop::new(entry.value.write()),
+            ttl,
+            constructing: false,
+        }
+    }
+
+    /// Calls the constructor and stores its result in the entry. Returns the write guard to
+    /// the new value on success.
+    pub(crate) fn construct<F>(mut self, ctor: F) -> DynResult<EntryWriteGuard<'a, K, V, P>>
+    where
+        F: FnOnce(&K) -> DynResult<V>,
+    {
+        self.constructing = true;
+        let value = ctor(&self.entry.key)?;
+        Ok(self.finish(value))
+    }
+
+    /// Awaits the future returned by the constructor and stores its result in the entry.
+    /// When this future is dropped before construction finished the entry is removed again.
+    #[cfg(feature = "async")]
+    pub(crate) async fn construct_async<F, Fut>(
+        mut self,
+        ctor: F,
+    ) -> DynResult<EntryWriteGuard<'a, K, V, P>>
+    where
+        F: FnOnce(&K) -> Fut,
+        Fut: Future<Output = DynResult<V>>,
+    {
+        self.constructing = true;
+        let value = ctor(&self.entry.key).await?;
+        Ok(self.finish(value))
+    }
+
+    /// Stores the constructed value and hands the lock over to a EntryWriteGuard.
+    fn finish(mut self, value: V) -> EntryWriteGuard<'a, K, V, P> {
+        **self.guard = Some(value);
+        self.bucket.inserts.fetch_add(1, Ordering::Relaxed);
+
+        if self
+            .bucket
+            .update_weight(self.entry, self.guard.as_ref().unwrap(), true)
+        {
+            // Only unused entries are evicted, never this one.
+            self.bucket.evict_overweight(&mut self.bucket.lock_map());
+        }
+
+        if self.ttl != u64::MAX {
+            let expires_at = timestamp().saturating_add(self.ttl);
+            self.bucket.schedule_expire(self.entry, expires_at);
+        }
+
+        // Construction succeeded, hand the lock over to a EntryWriteGuard.
+        let this = ManuallyDrop::new(self);
+        EntryWriteGuard {
+            bucket: this.bucket,
+            entry:  this.entry,
+            guard:  unsafe { std::ptr::read(&this.guard) },
+        }
+    }
+}
+
+impl<K, V, P> Drop for Placeholder<'_, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    fn drop(&mut self) {
+        // Only dropped without being constructed, the constructor failed, panicked or was
+        // cancelled, or a vacant entry was not filled.
+        if self.constructing {
+            self.bucket.ctor_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        // We still use the entry, thus it will be dropped by unuse_entry()
+        self.bucket.remove_entry(self.entry, EvictionCause::Removed);
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+            self.entry.notify_unlocked();
+            self.bucket.unuse_entry(self.entry);
+        }
     }
 }
 
-/// Guard for the read lock. Puts unused entries into the LRU list.
-pub struct EntryReadGuard<'a, K, V, const N: usize>
+/// The result of 'CacheDb::entry()', either an existing entry locked for writing or a vacant
+/// entry which can be filled with a value.
+pub enum CacheEntry<'a, K, V, P = Lru<K, V>>
 where
     K: KeyTraits,
+    P: EvictionPolicy<K, V>,
 {
-    pub(crate) bucket: &'a Bucket<K, V>,
+    /// The key is present, its value is locked for writing.
+    Occupied(EntryWriteGuard<'a, K, V, P>),
+    /// The key is not present.
+    Vacant(VacantEntry<'a, K, V, P>),
+}
+
+/// A vacant entry in the CacheDb. The entry is already inserted but holds no value yet, other
+/// threads querying the key wait until it is filled. Dropping the VacantEntry without
+/// inserting a value removes the entry again.
+pub struct VacantEntry<'a, K, V, P = Lru<K, V>>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    pub(crate) placeholder: Placeholder<'a, K, V, P>,
+}
+
+impl<'a, K, V, P> VacantEntry<'a, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    /// Returns the key of this entry.
+    pub fn key(&self) -> &K {
+        &self.placeholder.entry.key
+    }
+
+    /// Stores 'value' in the entry and returns it locked for writing.
+    pub fn insert(self, value: V) -> EntryWriteGuard<'a, K, V, P> {
+        self.placeholder.finish(value)
+    }
+
+    /// Stores the value returned by the constructor in the entry and returns it locked for
+    /// writing. When the constructor fails or panics the entry is removed.
+    pub fn insert_with<F>(self, ctor: F) -> DynResult<EntryWriteGuard<'a, K, V, P>>
+    where
+        F: FnOnce(&K) -> DynResult<V>,
+    {
+        self.placeholder.construct(ctor)
+    }
+}
+
+/// Releases an entry that is in use when dropped. Keeps futures waiting for a lock on the
+/// entry cancellation safe.
+#[cfg(feature = "async")]
+struct Unuse<'a, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    bucket: &'a Bucket<K, V, P>,
+    entry:  &'a Entry<K, V>,
+}
+
+#[cfg(feature = "async")]
+impl<K, V, P> Drop for Unuse<'_, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    fn drop(&mut self) {
+        unsafe { self.bucket.unuse_entry(self.entry) };
+    }
+}
+
+/// Checks the result of locking an entry that is in use. When locking failed or the entry
+/// got removed while waiting for the lock the entry is released again.
+fn checked_lock<K, V, P, G>(
+    bucket: &Bucket<K, V, P>,
+    entry: &Entry<K, V>,
+    result: Result<G, Error>,
+) -> Result<G, Error>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+    G: Deref<Target = Option<V>>,
+{
+    match result {
+        Ok(guard) if guard.is_some() => Ok(guard),
+        Ok(guard) => {
+            drop(guard);
+            entry.notify_unlocked();
+            unsafe { bucket.unuse_entry(entry) };
+            Err(Error::NoEntry)
+        }
+        Err(err) => {
+            if matches!(err, Error::LockUnavailable) {
+                bucket.lock_timeouts.fetch_add(1, Ordering::Relaxed);
+            }
+            unsafe { bucket.unuse_entry(entry) };
+            Err(err)
+        }
+    }
+}
+
+/// Guard for the read lock. Releases unused entries to the eviction policy.
+pub struct EntryReadGuard<'a, K, V, P = Lru<K, V>>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    pub(crate) bucket: &'a Bucket<K, V, P>,
     pub(crate) entry:  &'a Entry<K, V>,
-    pub(crate) guard:  RwLockReadGuard<'a, Option<V>>,
+    pub(crate) guard:  ManuallyDrop<RwLockReadGuard<'a, Option<V>>>,
 }
 
-impl<'a, K, V, const N: usize> EntryReadGuard<'_, K, V, N>
+impl<'a, K, V, P> EntryReadGuard<'a, K, V, P>
 where
     K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    /// Locks an entry that was acquired by 'Bucket::use_entry()'. When locking fails or the
+    /// entry got removed while waiting for the lock the entry is released again.
+    pub(crate) fn lock<M>(
+        bucket: &'a Bucket<K, V, P>,
+        entry: *const Entry<K, V>,
+        method: &M,
+    ) -> Result<Self, Error>
+    where
+        M: LockingMethod<'a, V>,
+    {
+        // Safety: the entry is in use and thus can not be dropped
+        let entry = unsafe { &*entry };
+        Self::from_lock(bucket, entry, method.read(&entry.value))
+    }
+
+    /// Locks an entry that was acquired by 'Bucket::use_entry()' without blocking the
+    /// thread. Like 'lock()' the entry is released again when locking fails, and as well when
+    /// the future is dropped while waiting.
+    #[cfg(feature = "async")]
+    pub(crate) async fn lock_async<M>(
+        bucket: &'a Bucket<K, V, P>,
+        entry: &'a Entry<K, V>,
+        method: &M,
+    ) -> Result<Self, Error>
+    where
+        M: AsyncLockingMethod,
+    {
+        let unuse = Unuse { bucket, entry };
+        let result = entry
+            .lock_async(method.deadline(), |lock| lock.try_read())
+            .await;
+        std::mem::forget(unuse);
+        Self::from_lock(bucket, entry, result)
+    }
+
+    /// Creates the guard from the result of locking an entry that is in use.
+    fn from_lock(
+        bucket: &'a Bucket<K, V, P>,
+        entry: &'a Entry<K, V>,
+        result: Result<RwLockReadGuard<'a, Option<V>>, Error>,
+    ) -> Result<Self, Error> {
+        checked_lock(bucket, entry, result).map(|guard| Self {
+            bucket,
+            entry,
+            guard: ManuallyDrop::new(guard),
+        })
+    }
+}
+
+impl<K, V, P> EntryReadGuard<'_, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
 {
     /// Mark the entry for expiration. When dropped it will be put in front of the LRU list
     /// and by that evicted soon. Use with care, when many entries become pushed to the front,
     /// they eventually bubble up again.
-    fn expire(&mut self) {
+    pub fn expire(&mut self) {
         self.entry.expire.store(true, Ordering::Relaxed);
     }
 }
 
-impl<'a, K, V, const N: usize> Drop for EntryReadGuard<'_, K, V, N>
+impl<K, V, P> Drop for EntryReadGuard<'_, K, V, P>
 where
     K: KeyTraits,
+    P: EvictionPolicy<K, V>,
 {
     fn drop(&mut self) {
-        self.bucket.unuse_entry(self.entry);
+        // The lock must be released before the entry, unuse_entry() may drop it.
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+            self.entry.notify_unlocked();
+            self.bucket.unuse_entry(self.entry);
+        }
     }
 }
 
-impl<'a, K, V, const N: usize> Deref for EntryReadGuard<'_, K, V, N>
+impl<K, V, P> Deref for EntryReadGuard<'_, K, V, P>
 where
     K: KeyTraits,
+    P: EvictionPolicy<K, V>,
 {
     type Target = V;
 
@@ -117,40 +519,142 @@ where
     }
 }
 
-/// Guard for the write lock. Puts unused entries into the LRU list.
-pub struct EntryWriteGuard<'a, K, V, const N: usize>
+/// Guard for the write lock. Releases unused entries to the eviction policy.
+pub struct EntryWriteGuard<'a, K, V, P = Lru<K, V>>
 where
     K: KeyTraits,
+    P: EvictionPolicy<K, V>,
 {
-    pub(crate) bucket: &'a Bucket<K, V>,
+    pub(crate) bucket: &'a Bucket<K, V, P>,
     pub(crate) entry:  &'a Entry<K, V>,
-    pub(crate) guard:  RwLockWriteGuard<'a, Option<V>>,
+    pub(crate) guard:  ManuallyDrop<RwLockWriteGuard<'a, Option<V>>>,
 }
 
-impl<'a, K, V, const N: usize> EntryWriteGuard<'_, K, V, N>
+impl<'a, K, V, P> EntryWriteGuard<'a, K, V, P>
 where
     K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    /// Locks an entry that was acquired by 'Bucket::use_entry()'. When locking fails or the
+    /// entry got removed while waiting for the lock the entry is released again.
+    pub(crate) fn lock<M>(
+        bucket: &'a Bucket<K, V, P>,
+        entry: *const Entry<K, V>,
+        method: &M,
+    ) -> Result<Self, Error>
+    where
+        M: LockingMethod<'a, V>,
+    {
+        // Safety: the entry is in use and thus can not be dropped
+        let entry = unsafe { &*entry };
+        Self::from_lock(bucket, entry, method.write(&entry.value))
+    }
+
+    /// Locks an entry that was acquired by 'Bucket::use_entry()' without blocking the
+    /// thread. Like 'lock()' the entry is released again when locking fails, and as well when
+    /// the future is dropped while waiting.
+    #[cfg(feature = "async")]
+    pub(crate) async fn lock_async<M>(
+        bucket: &'a Bucket<K, V, P>,
+        entry: &'a Entry<K, V>,
+        method: &M,
+    ) -> Result<Self, Error>
+    where
+        M: AsyncLockingMethod,
+    {
+        let unuse = Unuse { bucket, entry };
+        let result = entry
+            .lock_async(method.deadline(), |lock| lock.try_write())
+            .await;
+        std::mem::forget(unuse);
+        Self::from_lock(bucket, entry, result)
+    }
+
+    /// Creates the guard from the result of locking an entry that is in use.
+    fn from_lock(
+        bucket: &'a Bucket<K, V, P>,
+        entry: &'a Entry<K, V>,
+        result: Result<RwLockWriteGuard<'a, Option<V>>, Error>,
+    ) -> Result<Self, Error> {
+        checked_lock(bucket, entry, result).map(|guard| Self {
+            bucket,
+            entry,
+            guard: ManuallyDrop::new(guard),
+        })
+    }
+
+    /// Atomically downgrades the write lock into a read lock.
+    pub fn downgrade(self) -> EntryReadGuard<'a, K, V, P> {
+        self.update_weight();
+        let this = ManuallyDrop::new(self);
+        let guard = RwLockWriteGuard::downgrade(unsafe { std::ptr::read(&*this.guard) });
+        // other readers may proceed now
+        this.entry.notify_unlocked();
+        EntryReadGuard {
+            bucket: this.bucket,
+            entry:  this.entry,
+            guard:  ManuallyDrop::new(guard),
+        }
+    }
+
+    /// Atomically downgrades the write lock into an upgradable read lock.
+    pub fn downgrade_to_upgradable(self) -> EntryUpgradableGuard<'a, K, V, P> {
+        self.update_weight();
+        let this = ManuallyDrop::new(self);
+        let guard =
+            RwLockWriteGuard::downgrade_to_upgradable(unsafe { std::ptr::read(&*this.guard) });
+        this.entry.notify_unlocked();
+        EntryUpgradableGuard {
+            bucket: this.bucket,
+            entry:  this.entry,
+            guard:  ManuallyDrop::new(guard),
+        }
+    }
+
+    /// The value may have changed, thus its weight too. Values taken by 'CacheDb::take()'
+    /// leave a None behind.
+    fn update_weight(&self) {
+        if let Some(value) = &**self.guard {
+            if self.bucket.update_weight(self.entry, value, false) {
+                self.bucket.evict_overweight(&mut self.bucket.lock_map());
+            }
+        }
+    }
+}
+
+impl<K, V, P> EntryWriteGuard<'_, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
 {
     /// Mark the entry for expiration. When dropped it will be put in front of the LRU list
     /// and by that evicted soon. Use with care, when many entries become pushed to the front,
     /// they eventually bubble up again.
-    fn expire(&mut self) {
+    pub fn expire(&mut self) {
         self.entry.expire.store(true, Ordering::Relaxed);
     }
 }
 
-impl<'a, K, V, const N: usize> Drop for EntryWriteGuard<'_, K, V, N>
+impl<K, V, P> Drop for EntryWriteGuard<'_, K, V, P>
 where
     K: KeyTraits,
+    P: EvictionPolicy<K, V>,
 {
     fn drop(&mut self) {
-        self.bucket.unuse_entry(self.entry);
+        self.update_weight();
+        // The lock must be released before the entry, unuse_entry() may drop it.
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+            self.entry.notify_unlocked();
+            self.bucket.unuse_entry(self.entry);
+        }
     }
 }
 
-impl<'a, K, V, const N: usize> Deref for EntryWriteGuard<'_, K, V, N>
+impl<K, V, P> Deref for EntryWriteGuard<'_, K, V, P>
 where
     K: KeyTraits,
+    P: EvictionPolicy<K, V>,
 {
     type Target = V;
 
@@ -160,12 +664,630 @@ where
     }
 }
 
-impl<'a, K, V, const N: usize> DerefMut for EntryWriteGuard<'_, K, V, N>
+impl<K, V, P> DerefMut for EntryWriteGuard<'_, K, V, P>
 where
     K: KeyTraits,
+    P: EvictionPolicy<K, V>,
 {
     fn deref_mut(&mut self) -> &mut Self::Target {
         // unwrap is safe, the option is only None for a short time while constructing a new value
         (*self.guard).as_mut().unwrap()
     }
 }
+
+/// Guard for an upgradable read lock. Only one upgradable guard can exist for an entry at a
+/// time, along with any number of read guards. Can be upgraded to a 'EntryWriteGuard'
+/// atomically, without other writers in between. Releases unused entries to the eviction
+/// policy.
+pub struct EntryUpgradableGuard<'a, K, V, P = Lru<K, V>>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    pub(crate) bucket: &'a Bucket<K, V, P>,
+    pub(crate) entry:  &'a Entry<K, V>,
+    pub(crate) guard:  ManuallyDrop<RwLockUpgradableReadGuard<'a, Option<V>>>,
+}
+
+impl<'a, K, V, P> EntryUpgradableGuard<'a, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    /// Locks an entry that was acquired by 'Bucket::use_entry()'. When locking fails or the
+    /// entry got removed while waiting for the lock the entry is released again.
+    pub(crate) fn lock<M>(
+        bucket: &'a Bucket<K, V, P>,
+        entry: *const Entry<K, V>,
+        method: &M,
+    ) -> Result<Self, Error>
+    where
+        M: LockingMethod<'a, V>,
+    {
+        // Safety: the entry is in use and thus can not be dropped
+        let entry = unsafe { &*entry };
+        Self::from_lock(bucket, entry, method.upgradable_read(&entry.value))
+    }
+
+    /// Creates the guard from the result of locking an entry that is in use.
+    fn from_lock(
+        bucket: &'a Bucket<K, V, P>,
+        entry: &'a Entry<K, V>,
+        result: Result<RwLockUpgradableReadGuard<'a, Option<V>>, Error>,
+    ) -> Result<Self, Error> {
+        checked_lock(bucket, entry, result).map(|guard| Self {
+            bucket,
+            entry,
+            guard: ManuallyDrop::new(guard),
+        })
+    }
+
+    /// Atomically upgrades the lock to a write lock, waiting for all readers to leave as
+    /// defined by the 'method'. When the lock can not be obtained the guard is given back.
+    pub fn upgrade<M>(self, method: M) -> Result<EntryWriteGuard<'a, K, V, P>, Self>
+    where
+        M: LockingMethod<'a, V>,
+    {
+        let this = ManuallyDrop::new(self);
+        match method.upgrade(unsafe { std::ptr::read(&*this.guard) }) {
+            Ok(guard) => Ok(EntryWriteGuard {
+                bucket: this.bucket,
+                entry:  this.entry,
+                guard:  ManuallyDrop::new(guard),
+            }),
+            Err(guard) => {
+                this.bucket.lock_timeouts.fetch_add(1, Ordering::Relaxed);
+                Err(EntryUpgradableGuard {
+                    bucket: this.bucket,
+                    entry:  this.entry,
+                    guard:  ManuallyDrop::new(guard),
+                })
+            }
+        }
+    }
+
+    /// Atomically downgrades the lock into a read lock, allowing another upgradable lock.
+    pub fn downgrade(self) -> EntryReadGuard<'a, K, V, P> {
+        let this = ManuallyDrop::new(self);
+        let guard = RwLockUpgradableReadGuard::downgrade(unsafe { std::ptr::read(&*this.guard) });
+        this.entry.notify_unlocked();
+        EntryReadGuard {
+            bucket: this.bucket,
+            entry:  this.entry,
+            guard:  ManuallyDrop::new(guard),
+        }
+    }
+
+    /// Mark the entry for expiration. When dropped it will be put in front of the LRU list
+    /// and by that evicted soon. Use with care, when many entries become pushed to the front,
+    /// they eventually bubble up again.
+    pub fn expire(&mut self) {
+        self.entry.expire.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<K, V, P> Drop for EntryUpgradableGuard<'_, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    fn drop(&mut self) {
+        // The lock must be released before the entry, unuse_entry() may drop it.
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+            self.entry.notify_unlocked();
+            self.bucket.unuse_entry(self.entry);
+        }
+    }
+}
+
+impl<K, V, P> Deref for EntryUpgradableGuard<'_, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    type Target = V;
+
+    fn deref(&self) -> &Self::Target {
+        // unwrap is safe, the option is only None for a short time while constructing a new value
+        (*self.guard).as_ref().unwrap()
+    }
+}
+
+impl<'a, K, V, P> EntryReadGuard<'a, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    /// Makes a guard for a part of the value, the entry stays locked and in use until the
+    /// mapped guard is dropped. This is an associated function, like in parking_lot, to not
+    /// shadow methods of the value.
+    pub fn map<T, F>(s: Self, f: F) -> MappedEntryReadGuard<'a, K, V, T, P>
+    where
+        T: ?Sized,
+        F: FnOnce(&V) -> &T,
+    {
+        let value: *const T = f(&s);
+        MappedEntryReadGuard { guard: s, value }
+    }
+
+    /// Makes a guard for a part of the value when 'f' returns one, otherwise the original
+    /// guard is returned.
+    pub fn try_map<T, F>(s: Self, f: F) -> Result<MappedEntryReadGuard<'a, K, V, T, P>, Self>
+    where
+        T: ?Sized,
+        F: FnOnce(&V) -> Option<&T>,
+    {
+        match f(&s) {
+            Some(value) => {
+                let value: *const T = value;
+                Ok(MappedEntryReadGuard { guard: s, value })
+            }
+            None => Err(s),
+        }
+    }
+}
+
+impl<'a, K, V, P> EntryWriteGuard<'a, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    /// Makes a guard for a part of the value, the entry stays locked and in use until the
+    /// mapped guard is dropped. The weight of the whole value is updated then. This is an
+    /// associated function, like in parking_lot, to not shadow methods of the value.
+    pub fn map<T, F>(mut s: Self, f: F) -> MappedEntryWriteGuard<'a, K, V, T, P>
+    where
+        T: ?Sized,
+        F: FnOnce(&mut V) -> &mut T,
+    {
+        let value: *mut T = f(&mut s);
+        MappedEntryWriteGuard { guard: s, value }
+    }
+
+    /// Makes a guard for a part of the value when 'f' returns one, otherwise the original
+    /// guard is returned.
+    pub fn try_map<T, F>(mut s: Self, f: F) -> Result<MappedEntryWriteGuard<'a, K, V, T, P>, Self>
+    where
+        T: ?Sized,
+        F: FnOnce(&mut V) -> Option<&mut T>,
+    {
+        match f(&mut s) {
+            Some(value) => {
+                let value: *mut T = value;
+                Ok(MappedEntryWriteGuard { guard: s, value })
+            }
+            None => Err(s),
+        }
+    }
+}
+
+/// Read guard for a part of the value, made by 'EntryReadGuard::map()'. Keeps the original
+/// guard, which releases the entry when dropped.
+pub struct MappedEntryReadGuard<'a, K, V, T, P = Lru<K, V>>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+    T: ?Sized,
+{
+    guard: EntryReadGuard<'a, K, V, P>,
+    // Points into the value which is locked by 'guard'.
+    value: *const T,
+}
+
+impl<'a, K, V, T, P> MappedEntryReadGuard<'a, K, V, T, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+    T: ?Sized,
+{
+    /// Makes a guard for a part of the mapped value.
+    pub fn map<U, F>(s: Self, f: F) -> MappedEntryReadGuard<'a, K, V, U, P>
+    where
+        U: ?Sized,
+        F: FnOnce(&T) -> &U,
+    {
+        let value: *const U = f(&s);
+        MappedEntryReadGuard {
+            guard: s.guard,
+            value,
+        }
+    }
+
+    /// Makes a guard for a part of the mapped value when 'f' returns one, otherwise the
+    /// original guard is returned.
+    pub fn try_map<U, F>(s: Self, f: F) -> Result<MappedEntryReadGuard<'a, K, V, U, P>, Self>
+    where
+        U: ?Sized,
+        F: FnOnce(&T) -> Option<&U>,
+    {
+        match f(&s) {
+            Some(value) => {
+                let value: *const U = value;
+                Ok(MappedEntryReadGuard {
+                    guard: s.guard,
+                    value,
+                })
+            }
+            None => Err(s),
+        }
+    }
+}
+
+impl<K, V, T, P> Deref for MappedEntryReadGuard<'_, K, V, T, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+    T: ?Sized,
+{
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: the value is locked as long the guard exists
+        unsafe { &*self.value }
+    }
+}
+
+// Same as the guard it was made from, but the mapped part may be less shareable than the value.
+unsafe impl<'a, K, V, T, P> Send for MappedEntryReadGuard<'a, K, V, T, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+    T: ?Sized + Sync,
+    EntryReadGuard<'a, K, V, P>: Send,
+{
+}
+
+unsafe impl<'a, K, V, T, P> Sync for MappedEntryReadGuard<'a, K, V, T, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+    T: ?Sized + Sync,
+    EntryReadGuard<'a, K, V, P>: Sync,
+{
+}
+
+/// Write guard for a part of the value, made by 'EntryWriteGuard::map()'. Keeps the original
+/// guard, which updates the weight and releases the entry when dropped.
+pub struct MappedEntryWriteGuard<'a, K, V, T, P = Lru<K, V>>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+    T: ?Sized,
+{
+    guard: EntryWriteGuard<'a, K, V, P>,
+    // Points into the value which is locked by 'guard'.
+    value: *mut T,
+}
+
+impl<'a, K, V, T, P> MappedEntryWriteGuard<'a, K, V, T, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+    T: ?Sized,
+{
+    /// Makes a guard for a part of the mapped value.
+    pub fn map<U, F>(mut s: Self, f: F) -> MappedEntryWriteGuard<'a, K, V, U, P>
+    where
+        U: ?Sized,
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        let value: *mut U = f(&mut s);
+        MappedEntryWriteGuard {
+            guard: s.guard,
+            value,
+        }
+    }
+
+    /// Makes a guard for a part of the mapped value when 'f' returns one, otherwise the
+    /// original guard is returned.
+    pub fn try_map<U, F>(mut s: Self, f: F) -> Result<MappedEntryWriteGuard<'a, K, V, U, P>, Self>
+    where
+        U: ?Sized,
+        F: FnOnce(&mut T) -> Option<&mut U>,
+    {
+        match f(&mut s) {
+            Some(value) => {
+                let value: *mut U = value;
+                Ok(MappedEntryWriteGuard {
+                    guard: s.guard,
+                    value,
+                })
+            }
+            None => Err(s),
+        }
+    }
+}
+
+impl<K, V, T, P> Deref for MappedEntryWriteGuard<'_, K, V, T, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+    T: ?Sized,
+{
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: the value is locked as long the guard exists
+        unsafe { &*self.value }
+    }
+}
+
+impl<K, V, T, P> DerefMut for MappedEntryWriteGuard<'_, K, V, T, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+    T: ?Sized,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // Safety: the value is locked as long the guard exists
+        unsafe { &mut *self.value }
+    }
+}
+
+// Same as the guard it was made from, but the mapped part may be less shareable than the value.
+unsafe impl<'a, K, V, T, P> Send for MappedEntryWriteGuard<'a, K, V, T, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+    T: ?Sized + Send,
+    EntryWriteGuard<'a, K, V, P>: Send,
+{
+}
+
+unsafe impl<'a, K, V, T, P> Sync for MappedEntryWriteGuard<'a, K, V, T, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+    T: ?Sized + Sync,
+    EntryWriteGuard<'a, K, V, P>: Sync,
+{
+}
+
+/// Owned guard for the read lock. Holds a reference on the 'CacheDb' thus it is not bound to
+/// a lifetime and can be stored or returned freely. It can be sent to other threads when
+/// parking_lot's 'send_guard' feature is enabled.
+pub struct OwnedEntryReadGuard<K, V, P = Lru<K, V>, S = RandomState>
+where
+    K: KeyTraits + 'static,
+    V: 'static,
+    P: EvictionPolicy<K, V> + 'static,
+    S: BuildHasher,
+{
+    // borrows from 'cdb', must be dropped first
+    guard: ManuallyDrop<EntryReadGuard<'static, K, V, P>>,
+    cdb:   Arc<CacheDb<K, V, P, S>>,
+}
+
+impl<K, V, P, S> OwnedEntryReadGuard<K, V, P, S>
+where
+    K: KeyTraits + 'static,
+    V: 'static,
+    P: EvictionPolicy<K, V> + 'static,
+    S: BuildHasher,
+{
+    /// Safety: 'guard' must be obtained from the CacheDb 'cdb' points to.
+    pub(crate) unsafe fn new(
+        cdb: Arc<CacheDb<K, V, P, S>>,
+        guard: EntryReadGuard<'_, K, V, P>,
+    ) -> Self {
+        Self {
+            guard: ManuallyDrop::new(std::mem::transmute::<
+                EntryReadGuard<'_, K, V, P>,
+                EntryReadGuard<'static, K, V, P>,
+            >(guard)),
+            cdb,
+        }
+    }
+
+    /// Returns the CacheDb this guard keeps alive.
+    pub fn cache(&self) -> &Arc<CacheDb<K, V, P, S>> {
+        &self.cdb
+    }
+
+    /// Mark the entry for expiration, see 'EntryReadGuard::expire()'.
+    pub fn expire(&mut self) {
+        self.guard.expire();
+    }
+}
+
+impl<K, V, P, S> Drop for OwnedEntryReadGuard<K, V, P, S>
+where
+    K: KeyTraits + 'static,
+    V: 'static,
+    P: EvictionPolicy<K, V> + 'static,
+    S: BuildHasher,
+{
+    fn drop(&mut self) {
+        // The guard must be released before the CacheDb, this may be its last reference.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+    }
+}
+
+impl<K, V, P, S> Deref for OwnedEntryReadGuard<K, V, P, S>
+where
+    K: KeyTraits + 'static,
+    V: 'static,
+    P: EvictionPolicy<K, V> + 'static,
+    S: BuildHasher,
+{
+    type Target = V;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+/// Owned guard for the write lock. Like 'OwnedEntryReadGuard' it keeps the 'CacheDb' alive.
+pub struct OwnedEntryWriteGuard<K, V, P = Lru<K, V>, S = RandomState>
+where
+    K: KeyTraits + 'static,
+    V: 'static,
+    P: EvictionPolicy<K, V> + 'static,
+    S: BuildHasher,
+{
+    // borrows from 'cdb', must be dropped first
+    guard: ManuallyDrop<EntryWriteGuard<'static, K, V, P>>,
+    cdb:   Arc<CacheDb<K, V, P, S>>,
+}
+
+impl<K, V, P, S> OwnedEntryWriteGuard<K, V, P, S>
+where
+    K: KeyTraits + 'static,
+    V: 'static,
+    P: EvictionPolicy<K, V> + 'static,
+    S: BuildHasher,
+{
+    /// Safety: 'guard' must be obtained from the CacheDb 'cdb' points to.
+    pub(crate) unsafe fn new(
+        cdb: Arc<CacheDb<K, V, P, S>>,
+        guard: EntryWriteGuard<'_, K, V, P>,
+    ) -> Self {
+        Self {
+            guard: ManuallyDrop::new(std::mem::transmute::<
+                EntryWriteGuard<'_, K, V, P>,
+                EntryWriteGuard<'static, K, V, P>,
+            >(guard)),
+            cdb,
+        }
+    }
+
+    /// Returns the CacheDb this guard keeps alive.
+    pub fn cache(&self) -> &Arc<CacheDb<K, V, P, S>> {
+        &self.cdb
+    }
+
+    /// Mark the entry for expiration, see 'EntryWriteGuard::expire()'.
+    pub fn expire(&mut self) {
+        self.guard.expire();
+    }
+
+    /// Atomically downgrades the write lock into a read lock.
+    pub fn downgrade(self) -> OwnedEntryReadGuard<K, V, P, S> {
+        let this = ManuallyDrop::new(self);
+        // Safety: the guard and the cdb are moved out of 'this' which is never dropped
+        let (guard, cdb) = unsafe { (std::ptr::read(&*this.guard), std::ptr::read(&this.cdb)) };
+        OwnedEntryReadGuard {
+            guard: ManuallyDrop::new(guard.downgrade()),
+            cdb,
+        }
+    }
+}
+
+impl<K, V, P, S> Drop for OwnedEntryWriteGuard<K, V, P, S>
+where
+    K: KeyTraits + 'static,
+    V: 'static,
+    P: EvictionPolicy<K, V> + 'static,
+    S: BuildHasher,
+{
+    fn drop(&mut self) {
+        // The guard must be released before the CacheDb, this may be its last reference.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+    }
+}
+
+impl<K, V, P, S> Deref for OwnedEntryWriteGuard<K, V, P, S>
+where
+    K: KeyTraits + 'static,
+    V: 'static,
+    P: EvictionPolicy<K, V> + 'static,
+    S: BuildHasher,
+{
+    type Target = V;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<K, V, P, S> DerefMut for OwnedEntryWriteGuard<K, V, P, S>
+where
+    K: KeyTraits + 'static,
+    V: 'static,
+    P: EvictionPolicy<K, V> + 'static,
+    S: BuildHasher,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+/// Keeps an entry in use without locking it. In use entries are never evicted, the handle
+/// can lock the value again and again without looking it up in the CacheDb.
+pub struct EntryHandle<'a, K, V, P = Lru<K, V>>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    pub(crate) bucket: &'a Bucket<K, V, P>,
+    pub(crate) entry:  &'a Entry<K, V>,
+}
+
+impl<'a, K, V, P> EntryHandle<'a, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    /// Returns the key of the entry.
+    pub fn key(&self) -> &K {
+        &self.entry.key
+    }
+
+    /// Returns true when the entry was removed from the CacheDb or its time to live ended.
+    /// Locking it will fail with 'Error::NoEntry' then, a new entry has to be queried.
+    pub fn is_removed(&self) -> bool {
+        self.entry.removed.load(Ordering::Relaxed) != 0 || self.entry.is_expired()
+    }
+
+    /// Locks the entry for reading, the 'method' is the same as in 'CacheDb::get()'.
+    pub fn read<M>(&self, method: M) -> Result<EntryReadGuard<'a, K, V, P>, Error>
+    where
+        M: 'a + LockingMethod<'a, V>,
+    {
+        self.bucket.reuse_entry(self.entry);
+        let guard = EntryReadGuard::lock(self.bucket, self.entry, &method)?;
+        // the entry may have been removed while waiting for the lock
+        if self.is_removed() {
+            return Err(Error::NoEntry);
+        }
+        Ok(guard)
+    }
+
+    /// Locks the entry for writing, the 'method' is the same as in 'CacheDb::get()'.
+    pub fn write<M>(&self, method: M) -> Result<EntryWriteGuard<'a, K, V, P>, Error>
+    where
+        M: 'a + LockingMethod<'a, V>,
+    {
+        self.bucket.reuse_entry(self.entry);
+        let guard = EntryWriteGuard::lock(self.bucket, self.entry, &method)?;
+        if self.is_removed() {
+            return Err(Error::NoEntry);
+        }
+        Ok(guard)
+    }
+}
+
+impl<K, V, P> Clone for EntryHandle<'_, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    fn clone(&self) -> Self {
+        self.bucket.reuse_entry(self.entry);
+        EntryHandle {
+            bucket: self.bucket,
+            entry:  self.entry,
+        }
+    }
+}
+
+impl<K, V, P> Drop for EntryHandle<'_, K, V, P>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+{
+    fn drop(&mut self) {
+        unsafe { self.bucket.unuse_entry(self.entry) };
+    }
+}
@@ -32,6 +32,10 @@
 //! Is actually simple, the returned guard has a rust lifetime bound to the CacheDB
 //! object.  Thus no access can outlive the hosting collection.
 //!
+//! The owned guards returned by 'get_owned()' and friends erase this lifetime. Instead they
+//! hold an 'Arc' on the CacheDB and release their inner guard before it, thus the hosting
+//! collection is kept alive for as long as any access exists.
+//!
 //!
 //! Proof that no data races exist
 //! ------------------------------
@@ -77,6 +81,57 @@
 //! list. Whenever a CacheDb decides to expire Items these are taken from the head of the
 //! lru-list and dropped.
 //!
+//! The LRU is the default 'EvictionPolicy'.  Other policies can be plugged in by the 'P' type
+//! parameter of 'CacheDb', they decide which entries are evicted while the CacheDb still
+//! decides how many.  'WTinyLfu' is a scan resistant alternative which admits entries to its
+//! main region only when they are accessed more often than the ones they would replace.
+//! 'AdaptiveReplacement' keeps entries used once apart from entries used repeatedly and
+//! adapts the split between both by remembering the keys of evicted entries.
+//!
+//! Entries can have a time to live, either given at construction with
+//! 'get_or_insert_with_ttl()' or by the 'config_default_ttl()'.  Expired entries are treated
+//! as missing and reclaimed independently of the LRU configuration.  Likewise entries that
+//! were not used for 'config_time_to_idle()' are evicted from the front of the lru-list.
+//!
+//! Memory usage can be bounded by a 'config_weigher()' which computes the weight of each entry
+//! and 'config_max_weight()'.  Entries are evicted in LRU order until the weight of all
+//! entries is within this budget.
+//!
+//! Entries kept by an 'EntryHandle' count as in use, they are never evicted and can be locked
+//! through the handle without looking them up again.
+//!
+//! A listener registered with 'on_evict()' receives the key and value of every entry that
+//! leaves the CacheDb together with its 'EvictionCause'.
+//!
+//!
+//! Iteration
+//! =========
+//!
+//! 'keys()' and 'snapshot_keys()' return the keys of all entries, 'for_each()' locks the
+//! entries one by one to visit their values.  The buckets are visited one after another,
+//! thus the result is not a consistent snapshot of the whole CacheDb when it is modified
+//! concurrently.  No bucket stays locked while user code runs.
+//!
+//!
+//! Statistics
+//! ==========
+//!
+//! 'stats()' returns counters for hits, misses, inserts, evictions and more, per bucket and
+//! in total.  With the 'metrics' feature enabled these can be rendered in the Prometheus text
+//! exposition format by 'CacheStats::to_prometheus()' or reported to the 'metrics' crate
+//! facade by 'CacheStats::record_metrics()'.
+//!
+//!
+//! Async
+//! =====
+//!
+//! With the 'async' feature enabled 'get_async()', 'get_mut_async()' and
+//! 'get_or_insert_async()' wait for entry locks without blocking the thread.  Timeouts are
+//! given by the same 'Duration' and 'Instant' locking methods and require a tokio runtime with
+//! the time driver enabled.  'get_or_insert_async()' takes an async constructor, concurrent
+//! callers for the same key await the value it constructs.  This feature enables the
+//! 'send_guard' feature of parking_lot since locks may be held across await points.
+//!
 //!
 //! TESTS
 //! =====
@@ -91,63 +146,135 @@
 //! The default values are rather small to make the test suite complete in short time. For dedicated
 //! stress testing at least STRESS_ITERATIONS and STRESS_THREADS has to be incresed significantly.
 //! Try 'STRESS_ITERATIONS=10000 STRESS_RANGE=10000 STRESS_THREADS=10000' for some harder test.
+//!
+//! 'cargo bench' measures lookups and inserts with small keys where hashing is a significant
+//! part of the costs.
 #![allow(clippy::type_complexity)]
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::collections::HashSet;
-use std::pin::Pin;
+use std::borrow::Borrow;
+use std::sync::atomic::Ordering;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+use std::thread;
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
+pub use intrusive_collections;
 use intrusive_collections::UnsafeRef;
-use parking_lot::{MutexGuard, RwLockWriteGuard};
 
 mod entry;
-use crate::entry::Entry;
-pub use crate::entry::{EntryReadGuard, EntryWriteGuard, KeyTraits};
+use crate::entry::Placeholder;
+pub use crate::entry::{
+    CacheEntry, Entry, EntryAdapter, EntryHandle, EntryReadGuard, EntryUpgradableGuard,
+    EntryWriteGuard, KeyTraits, MappedEntryReadGuard, MappedEntryWriteGuard, OwnedEntryReadGuard,
+    OwnedEntryWriteGuard, VacantEntry,
+};
 
 mod bucket;
-use crate::bucket::Bucket;
-pub use crate::bucket::Bucketize;
+use crate::bucket::{Bucket, MapLock};
+pub use crate::bucket::EvictionCause;
+
+mod policy;
+pub use crate::policy::{AdaptiveReplacement, EvictionPolicy, Lru, WTinyLfu};
 
 mod locking_method;
 pub use crate::locking_method::*;
 
-/// CacheDb implements the concurrent (bucketed) Key/Value store.  Keys must implement
-/// 'Bucketize' which has more lax requirments than a full hash implmementation.  'N' is the
-/// number of buckets to use. This is const because less dereferencing and management
-/// overhead.  Buckets by themself are not very expensive thus it is recommended to use a
-/// generous large enough number here.  Think about expected number of concurrenct accesses
-/// times four.
-#[derive(Debug)]
-pub struct CacheDb<K, V, const N: usize>
+mod stats;
+pub use crate::stats::{BucketStats, CacheStats};
+
+#[cfg(feature = "metrics")]
+mod metrics;
+
+/// CacheDb implements the concurrent (bucketed) Key/Value store.  The number of buckets is
+/// chosen when the CacheDb is created.  Buckets by themself are not very expensive thus it is
+/// recommended to use a generous large enough number here.  Think about expected number of
+/// concurrenct accesses times four. 'P' is the 'EvictionPolicy' which selects the entries to
+/// evict, 'Lru' by default.  'S' is the 'BuildHasher' used for selecting the bucket of a key
+/// and within the buckets.  The default 'RandomState' is seeded randomly for each CacheDb,
+/// thus keys can not be crafted to fall all into the same bucket.  Faster hashers can be
+/// used when keys are trusted.
+pub struct CacheDb<K, V, P = Lru<K, V>, S = RandomState>
 where
     K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+    S: BuildHasher,
 {
-    buckets:      [Bucket<K, V>; N],
-    lru_disabled: AtomicU32,
+    buckets: Box<[Bucket<K, V, P>]>,
+    hasher:  S,
 }
 
-impl<K, V, const N: usize> CacheDb<K, V, N>
+impl<K, V, P, S> CacheDb<K, V, P, S>
 where
     K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+    S: BuildHasher,
 {
-    /// Create a new CacheDb
-    pub fn new() -> CacheDb<K, V, N> {
+    /// Create a new CacheDb with four buckets per available CPU.
+    pub fn new() -> CacheDb<K, V, P, S>
+    where
+        S: Default,
+    {
+        Self::with_hasher(S::default())
+    }
+
+    /// Create a new CacheDb with the given number of buckets.
+    pub fn with_buckets(buckets: usize) -> CacheDb<K, V, P, S>
+    where
+        S: Default,
+    {
+        Self::with_buckets_and_hasher(buckets, S::default())
+    }
+
+    /// Create a new CacheDb with four buckets per available CPU using the given hasher.
+    pub fn with_hasher(hasher: S) -> CacheDb<K, V, P, S> {
+        let cpus = thread::available_parallelism().map_or(1, |cpus| cpus.get());
+        Self::with_buckets_and_hasher(cpus * 4, hasher)
+    }
+
+    /// Create a new CacheDb with the given number of buckets using the given hasher.
+    pub fn with_buckets_and_hasher(buckets: usize, hasher: S) -> CacheDb<K, V, P, S> {
+        assert!(buckets > 0);
         CacheDb {
-            buckets:      [(); N].map(|()| Bucket::new()),
-            lru_disabled: AtomicU32::new(0),
+            buckets: (0..buckets).map(|_| Bucket::new()).collect(),
+            hasher,
         }
     }
 
+    /// Hashes 'key' and returns the bucket it falls into together with the hash. The hash is
+    /// reused for the lookup within the bucket.
+    fn bucket<Q>(&self, key: &Q) -> (&Bucket<K, V, P>, u64)
+    where
+        Q: ?Sized + Hash,
+    {
+        let hash = self.hasher.hash_one(key);
+        (&self.buckets[self.bucket_index(hash)], hash)
+    }
+
+    /// Selects the bucket for a hash. Fast hashers for trusted keys may return the key itself,
+    /// thus the hash is mixed first to spread all its bits into the upper half. The maps
+    /// within the buckets use the lower bits for indexing, the bucket is selected by the
+    /// upper half.
+    fn bucket_index(&self, hash: u64) -> usize {
+        let mixed = hash.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        ((mixed >> 32) % self.buckets.len() as u64) as usize
+    }
+
     /// queries an entry and detaches it from the LRU
-    fn query_entry(&self, key: &K) -> Result<(&Bucket<K, V>, *const Entry<K, V>), Error> {
-        let bucket = &self.buckets[key.bucket::<N>()];
-        let map_lock = bucket.lock_map();
+    fn query_entry<Q>(&self, key: &Q) -> Result<(&Bucket<K, V, P>, *const Entry<K, V>), Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (bucket, hash) = self.bucket(key);
+        let mut map_lock = bucket.lock_map();
 
-        if let Some(entry) = map_lock.get(key) {
+        if let Some(entry) = bucket.get_entry(&mut map_lock, hash, key) {
+            bucket.hits.fetch_add(1, Ordering::Relaxed);
             bucket.use_entry(entry);
-            Ok((bucket, &**entry))
+            Ok((bucket, entry))
         } else {
+            bucket.misses.fetch_add(1, Ordering::Relaxed);
             Err(Error::NoEntry)
         }
     }
@@ -161,81 +288,206 @@ where
     ///     when the lock can't be obtained within this time.
     ///   * Instant: tries to lock the entry until some point in time, returns 'Error::LockUnavailable'
     ///     when the lock can't be obtained in time.
+    ///
     ///   All of the can be wraped in 'Recursive()' to allow a thread to relock any lock it already helds.
-    pub fn get<'a, M>(&'a self, method: M, key: &K) -> Result<EntryReadGuard<K, V, N>, Error>
+    ///
+    /// The 'key' can be any type the keys can be borrowed as, a 'String' key can be queried by
+    /// a '&str'. This applies to all functions that only look up existing entries.
+    pub fn get<'a, M, Q>(&'a self, method: M, key: &Q) -> Result<EntryReadGuard<'a, K, V, P>, Error>
     where
         M: 'a + LockingMethod<'a, V>,
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
     {
         let (bucket, entry_ptr) = self.query_entry(key)?;
-        Ok(EntryReadGuard {
-            bucket,
-            entry: unsafe { &*entry_ptr },
-            guard: unsafe { LockingMethod::read(&method, &(*entry_ptr).value)? },
-        })
+        EntryReadGuard::lock(bucket, entry_ptr, &method)
     }
 
     /// Query the Entry associated with key for writing
-    pub fn get_mut<'a, M>(&'a self, method: M, key: &K) -> Result<EntryWriteGuard<K, V, N>, Error>
+    pub fn get_mut<'a, M, Q>(
+        &'a self,
+        method: M,
+        key: &Q,
+    ) -> Result<EntryWriteGuard<'a, K, V, P>, Error>
+    where
+        M: 'a + LockingMethod<'a, V>,
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (bucket, entry_ptr) = self.query_entry(key)?;
+        EntryWriteGuard::lock(bucket, entry_ptr, &method)
+    }
+
+    /// Query the Entry associated with key for reading with an upgradable lock. Only one
+    /// upgradable lock can be held for an entry while others may still read it.  The 'method'
+    /// applies to locking, 'EntryUpgradableGuard::upgrade()' takes its own.
+    pub fn get_upgradable<'a, M, Q>(
+        &'a self,
+        method: M,
+        key: &Q,
+    ) -> Result<EntryUpgradableGuard<'a, K, V, P>, Error>
+    where
+        M: 'a + LockingMethod<'a, V>,
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (bucket, entry_ptr) = self.query_entry(key)?;
+        EntryUpgradableGuard::lock(bucket, entry_ptr, &method)
+    }
+
+    /// Iterates over the keys of all entries. The keys of a bucket are collected when the
+    /// iteration reaches it, thus changes in buckets not visited yet are reflected. The
+    /// CacheDb is not locked while the iterator is used.
+    pub fn keys(&self) -> impl Iterator<Item = K> + '_ {
+        self.buckets.iter().flat_map(|bucket| bucket.keys())
+    }
+
+    /// Collects the keys of all entries. The buckets are locked one after another, each
+    /// contributes the keys it holds at that time.
+    pub fn snapshot_keys(&self) -> Vec<K> {
+        self.keys().collect()
+    }
+
+    /// Calls 'f' with the key and value of every entry. Entries are read locked one by one
+    /// with the given 'method', entries which can not be locked, like busy entries with
+    /// 'TryLock', are skipped. No bucket is locked while 'f' runs, thus 'f' may use the
+    /// CacheDb. It must not write lock the entry it is called for, this would deadlock.
+    /// Visiting entries does not count as access, the eviction order is left alone.
+    pub fn for_each<'a, M, F>(&'a self, method: M, mut f: F)
     where
         M: 'a + LockingMethod<'a, V>,
+        F: FnMut(&K, &V),
+    {
+        for bucket in self.buckets.iter() {
+            for (hash, entry_ptr) in bucket.entry_ptrs() {
+                // entries removed in the meantime are skipped
+                let Some(entry) = bucket.peek_stored(hash, entry_ptr) else {
+                    continue;
+                };
+                if let Ok(guard) = EntryReadGuard::lock(bucket, entry, &method) {
+                    if entry.removed.load(Ordering::Relaxed) == 0 && !entry.is_expired() {
+                        f(&entry.key, &guard);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Query the Entry associated with key and return a handle which keeps it in use without
+    /// locking it. Locking the entry through the handle skips the lookup.
+    pub fn handle<Q>(&self, key: &Q) -> Result<EntryHandle<'_, K, V, P>, Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
     {
         let (bucket, entry_ptr) = self.query_entry(key)?;
-        Ok(EntryWriteGuard {
+        Ok(EntryHandle {
             bucket,
+            // Safety: the entry is in use and thus can not be dropped
             entry: unsafe { &*entry_ptr },
-            guard: unsafe { LockingMethod::write(&method, &(*entry_ptr).value)? },
         })
     }
 
+    /// Query the Entry associated with key for reading. The returned guard keeps the CacheDb
+    /// alive and is not bound to a lifetime.
+    pub fn get_owned<'a, M, Q>(
+        self: &'a Arc<Self>,
+        method: M,
+        key: &Q,
+    ) -> Result<OwnedEntryReadGuard<K, V, P, S>, Error>
+    where
+        K: 'static + Borrow<Q>,
+        V: 'static,
+        P: 'static,
+        M: 'a + LockingMethod<'a, V>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let guard = self.get(method, key)?;
+        // Safety: the guard borrows from the CacheDb the Arc keeps alive
+        Ok(unsafe { OwnedEntryReadGuard::new(Arc::clone(self), guard) })
+    }
+
+    /// Query the Entry associated with key for writing. The returned guard keeps the CacheDb
+    /// alive and is not bound to a lifetime.
+    pub fn get_mut_owned<'a, M, Q>(
+        self: &'a Arc<Self>,
+        method: M,
+        key: &Q,
+    ) -> Result<OwnedEntryWriteGuard<K, V, P, S>, Error>
+    where
+        K: 'static + Borrow<Q>,
+        V: 'static,
+        P: 'static,
+        M: 'a + LockingMethod<'a, V>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let guard = self.get_mut(method, key)?;
+        // Safety: the guard borrows from the CacheDb the Arc keeps alive
+        Ok(unsafe { OwnedEntryWriteGuard::new(Arc::clone(self), guard) })
+    }
+
     // queries an entry and detaches it from the LRU or creates a new one
     fn query_or_insert_entry(
         &self,
         key: &K,
     ) -> std::result::Result<
-        (&Bucket<K, V>, *const Entry<K, V>),
-        (
-            &Bucket<K, V>,
-            *const Entry<K, V>,
-            MutexGuard<HashSet<Pin<Box<entry::Entry<K, V>>>>>,
-        ),
+        (&Bucket<K, V, P>, *const Entry<K, V>),
+        (&Bucket<K, V, P>, *const Entry<K, V>, MapLock<'_, K, V, P>),
     > {
-        let bucket = &self.buckets[key.bucket::<N>()];
+        let (bucket, hash) = self.bucket(key);
         let mut map_lock = bucket.lock_map();
 
-        if let Some(entry) = map_lock.get(key) {
-            bucket.use_entry(entry);
-            Ok((bucket, &**entry))
-        } else {
-            let entry = Box::pin(Entry::new(key.clone()));
-            let entry_ptr: *const Entry<K, V> = &*entry;
-            map_lock.insert(entry);
-            Err((bucket, entry_ptr, map_lock))
+        match map_lock.get_or_insert(hash, key) {
+            Ok(entry) => {
+                bucket.hits.fetch_add(1, Ordering::Relaxed);
+                // Safety: the entry is owned by the map which we have locked
+                bucket.use_entry(unsafe { &*entry });
+                Ok((bucket, entry))
+            }
+            Err(entry_ptr) => {
+                bucket.misses.fetch_add(1, Ordering::Relaxed);
+                Err((bucket, entry_ptr, map_lock))
+            }
         }
     }
 
+    /// Creates a placeholder for a new entry, the write lock on it is taken before the map
+    /// lock is released to avoid races. When the placeholder is dropped without being
+    /// constructed it is removed again. Without an explicit 'ttl' the configured default time
+    /// to live is used.
+    fn new_placeholder<'a>(
+        &self,
+        bucket: &'a Bucket<K, V, P>,
+        entry_ptr: *const Entry<K, V>,
+        mut map_lock: MapLock<'_, K, V, P>,
+        ttl: Option<Duration>,
+    ) -> Placeholder<'a, K, V, P> {
+        bucket.reclaim_expired(&mut map_lock);
+        bucket.maybe_evict(&mut map_lock);
+
+        let ttl = match ttl {
+            Some(_) => entry::nanos(ttl),
+            None => bucket.default_ttl.load(Ordering::Relaxed),
+        };
+        Placeholder::new(bucket, entry_ptr, ttl)
+    }
+
     /// Tries to insert an entry with the given constructor.  Returns Ok(true) when the
     /// constructor was called, Ok(false) when and item is already present under the given key
-    /// or some Err() in case the constructor failed.
+    /// or some Err() in case the constructor failed. A failing or panicking constructor
+    /// leaves no entry behind. Existing values are kept, 'upsert()' overwrites them.
     pub fn insert<F>(&self, key: &K, ctor: F) -> DynResult<bool>
     where
         F: FnOnce(&K) -> DynResult<V>,
     {
         match self.query_or_insert_entry(key) {
-            Ok(_) => Ok(false),
-            Err((bucket, entry_ptr, mut map_lock)) => {
-                if self.lru_disabled.load(Ordering::Relaxed) == 0 {
-                    bucket.maybe_evict(&mut map_lock);
-                }
-
-                // need write lock for the ctor, before releasing the map to avoid a race.
-                let mut wguard = unsafe { LockingMethod::write(&Blocking, &(*entry_ptr).value)? };
-
-                // release the map_lock, we dont need it anymore
-                drop(map_lock);
-
-                // but we have wguard here which allows us to constuct the inner guts
-                *wguard = Some(ctor(key)?);
-
+            Ok((bucket, entry_ptr)) => {
+                unsafe { bucket.unuse_entry(entry_ptr) };
+                Ok(false)
+            }
+            Err((bucket, entry_ptr, map_lock)) => {
+                self.new_placeholder(bucket, entry_ptr, map_lock, None)
+                    .construct(ctor)?;
                 Ok(true)
             }
         }
@@ -244,103 +496,308 @@ where
     // TODO: The ctor function may become double nested Fn() -> Result(Fn() -> Result(Value)) The
     //       outer can acquire resouces while the cachedb is (temporary) unlocked and returns the
     //       real ctor then.
-    /// Query an Entry for reading or construct it (atomically)
+    /// Query an Entry for reading or construct it (atomically). When the constructor fails
+    /// or panics no entry is left behind. Other threads waiting on an entry whose construction
+    /// failed will retry the query and eventually call their constructor.
     pub fn get_or_insert<'a, M, F>(
         &'a self,
         method: M,
         key: &K,
         ctor: F,
-    ) -> DynResult<EntryReadGuard<K, V, N>>
+    ) -> DynResult<EntryReadGuard<'a, K, V, P>>
     where
         F: FnOnce(&K) -> DynResult<V>,
         M: 'a + LockingMethod<'a, V>,
     {
-        match self.query_or_insert_entry(key) {
-            Ok((bucket, entry_ptr)) => Ok(EntryReadGuard {
-                bucket,
-                entry: unsafe { &*entry_ptr },
-                guard: unsafe { LockingMethod::read(&method, &(*entry_ptr).value)? },
-            }),
-            Err((bucket, entry_ptr, mut map_lock)) => {
-                if self.lru_disabled.load(Ordering::Relaxed) == 0 {
-                    bucket.maybe_evict(&mut map_lock);
-                }
-
-                // need write lock for the ctor, before releasing the map to avoid a race.
-                let mut wguard =
-                    unsafe { LockingMethod::write(&Blocking, &(*entry_ptr).value).unwrap() };
-
-                // release the map_lock, we dont need it anymore
-                drop(map_lock);
+        self.get_or_insert_ttl(method, key, None, ctor)
+    }
 
-                // but we have wguard here which allows us to constuct the inner guts
-                *wguard = Some(ctor(key)?);
+    /// Query an Entry for reading or construct it (atomically) with the given time to live.
+    /// Once the 'ttl' passed the entry is treated as missing and will be constructed again.
+    /// The 'ttl' only applies when the entry gets constructed.
+    pub fn get_or_insert_with_ttl<'a, M, F>(
+        &'a self,
+        method: M,
+        key: &K,
+        ttl: Duration,
+        ctor: F,
+    ) -> DynResult<EntryReadGuard<'a, K, V, P>>
+    where
+        F: FnOnce(&K) -> DynResult<V>,
+        M: 'a + LockingMethod<'a, V>,
+    {
+        self.get_or_insert_ttl(method, key, Some(ttl), ctor)
+    }
 
-                // Finally downgrade the lock to a readlock and return the Entry
-                Ok(EntryReadGuard {
-                    bucket,
-                    entry: unsafe { &*entry_ptr },
-                    guard: RwLockWriteGuard::downgrade(wguard),
-                })
+    fn get_or_insert_ttl<'a, M, F>(
+        &'a self,
+        method: M,
+        key: &K,
+        ttl: Option<Duration>,
+        ctor: F,
+    ) -> DynResult<EntryReadGuard<'a, K, V, P>>
+    where
+        F: FnOnce(&K) -> DynResult<V>,
+        M: 'a + LockingMethod<'a, V>,
+    {
+        loop {
+            match self.query_or_insert_entry(key) {
+                Ok((bucket, entry_ptr)) => match EntryReadGuard::lock(bucket, entry_ptr, &method) {
+                    // the entry was removed while we waited for it, try again
+                    Err(Error::NoEntry) => continue,
+                    result => return Ok(result?),
+                },
+                Err((bucket, entry_ptr, map_lock)) => {
+                    // Finally downgrade the lock to a readlock and return the Entry
+                    return Ok(self
+                        .new_placeholder(bucket, entry_ptr, map_lock, ttl)
+                        .construct(ctor)?
+                        .downgrade());
+                }
             }
         }
     }
 
-    /// Query an Entry for writing or construct it (atomically)
+    /// Query an Entry for reading or construct it (atomically) like 'get_or_insert()'. The
+    /// returned guard keeps the CacheDb alive and is not bound to a lifetime.
+    pub fn get_or_insert_owned<'a, M, F>(
+        self: &'a Arc<Self>,
+        method: M,
+        key: &K,
+        ctor: F,
+    ) -> DynResult<OwnedEntryReadGuard<K, V, P, S>>
+    where
+        K: 'static,
+        V: 'static,
+        P: 'static,
+        F: FnOnce(&K) -> DynResult<V>,
+        M: 'a + LockingMethod<'a, V>,
+    {
+        let guard = self.get_or_insert(method, key, ctor)?;
+        // Safety: the guard borrows from the CacheDb the Arc keeps alive
+        Ok(unsafe { OwnedEntryReadGuard::new(Arc::clone(self), guard) })
+    }
+
+    /// Query an Entry for writing or construct it (atomically) like 'get_or_insert_mut()'.
+    /// The returned guard keeps the CacheDb alive and is not bound to a lifetime.
+    pub fn get_or_insert_mut_owned<'a, M, F>(
+        self: &'a Arc<Self>,
+        method: M,
+        key: &K,
+        ctor: F,
+    ) -> DynResult<OwnedEntryWriteGuard<K, V, P, S>>
+    where
+        K: 'static,
+        V: 'static,
+        P: 'static,
+        F: FnOnce(&K) -> DynResult<V>,
+        M: 'a + LockingMethod<'a, V>,
+    {
+        let guard = self.get_or_insert_mut(method, key, ctor)?;
+        // Safety: the guard borrows from the CacheDb the Arc keeps alive
+        Ok(unsafe { OwnedEntryWriteGuard::new(Arc::clone(self), guard) })
+    }
+
+    /// Query an Entry for writing or construct it (atomically). Failing constructors are
+    /// handled the same as in 'get_or_insert()'.
     pub fn get_or_insert_mut<'a, M, F>(
         &'a self,
         method: M,
         key: &K,
         ctor: F,
-    ) -> DynResult<EntryWriteGuard<K, V, N>>
+    ) -> DynResult<EntryWriteGuard<'a, K, V, P>>
     where
         F: FnOnce(&K) -> DynResult<V>,
         M: 'a + LockingMethod<'a, V>,
     {
-        match self.query_or_insert_entry(key) {
-            Ok((bucket, entry_ptr)) => Ok(EntryWriteGuard {
-                bucket,
-                entry: unsafe { &*entry_ptr },
-                guard: unsafe { LockingMethod::write(&method, &(*entry_ptr).value)? },
-            }),
-            Err((bucket, entry_ptr, mut map_lock)) => {
-                if self.lru_disabled.load(Ordering::Relaxed) == 0 {
-                    bucket.maybe_evict(&mut map_lock);
+        loop {
+            match self.query_or_insert_entry(key) {
+                Ok((bucket, entry_ptr)) => {
+                    match EntryWriteGuard::lock(bucket, entry_ptr, &method) {
+                        // the entry was removed while we waited for it, try again
+                        Err(Error::NoEntry) => continue,
+                        result => return Ok(result?),
+                    }
                 }
+                Err((bucket, entry_ptr, map_lock)) => {
+                    return self
+                        .new_placeholder(bucket, entry_ptr, map_lock, None)
+                        .construct(ctor);
+                }
+            }
+        }
+    }
 
-                // need write lock for the ctor, before releasing the map to avoid a race.
-                let mut wguard =
-                    unsafe { LockingMethod::write(&Blocking, &(*entry_ptr).value).unwrap() };
-
-                // release the map_lock, we dont need it anymore
-                drop(map_lock);
+    /// Query an Entry for writing or create a vacant one (atomically). Allows to decide
+    /// whether and how to construct a value after the lookup, without races between checking
+    /// and inserting. The 'method' applies only to locking an existing entry. While a
+    /// 'VacantEntry' is held, other threads querying the key wait for it.
+    pub fn entry<'a, M>(&'a self, method: M, key: &K) -> Result<CacheEntry<'a, K, V, P>, Error>
+    where
+        M: 'a + LockingMethod<'a, V>,
+    {
+        loop {
+            match self.query_or_insert_entry(key) {
+                Ok((bucket, entry_ptr)) => {
+                    match EntryWriteGuard::lock(bucket, entry_ptr, &method) {
+                        // the entry was removed while we waited for it, try again
+                        Err(Error::NoEntry) => continue,
+                        result => return result.map(CacheEntry::Occupied),
+                    }
+                }
+                Err((bucket, entry_ptr, map_lock)) => {
+                    return Ok(CacheEntry::Vacant(VacantEntry {
+                        placeholder: self.new_placeholder(bucket, entry_ptr, map_lock, None),
+                    }));
+                }
+            }
+        }
+    }
 
-                // but we have wguard here which allows us to constuct the inner guts
-                *wguard = Some(ctor(key)?);
+    /// Inserts the value or overwrites the value associated with key. The write lock on an
+    /// existing entry is acquired blocking. The previous value is passed to the eviction
+    /// listener with 'EvictionCause::Replaced'.
+    pub fn upsert(&self, key: &K, value: V) {
+        match self.entry(Blocking, key) {
+            Ok(CacheEntry::Occupied(mut guard)) => {
+                let old = std::mem::replace(&mut *guard, value);
+                let bucket = guard.bucket;
+                // the listener must not be called while the entry is locked
+                drop(guard);
+                bucket.notify_replaced(key, old);
+            }
+            Ok(CacheEntry::Vacant(vacant)) => {
+                vacant.insert(value);
+            }
+            Err(_) => unreachable!("blocking locks never fail"),
+        }
+    }
 
-                // Finally downgrade the lock to a readlock and return the Entry
-                Ok(EntryWriteGuard {
-                    bucket,
-                    entry: unsafe { &*entry_ptr },
-                    guard: wguard,
-                })
+    /// Inserts the value or overwrites the value associated with key and returns the
+    /// previous value. The write lock on an existing entry is acquired with the given
+    /// 'method'.  When this fails the value is not stored and the error is returned. Since the
+    /// previous value is handed to the caller the eviction listener is not called.
+    pub fn replace<'a, M>(&'a self, method: M, key: &K, value: V) -> Result<Option<V>, Error>
+    where
+        M: 'a + LockingMethod<'a, V>,
+    {
+        match self.entry(method, key)? {
+            CacheEntry::Occupied(mut guard) => Ok(Some(std::mem::replace(&mut *guard, value))),
+            CacheEntry::Vacant(vacant) => {
+                vacant.insert(value);
+                Ok(None)
             }
         }
     }
 
+    /// Atomically computes a new value from the value associated with key, 'f' is called
+    /// with 'None' when the key is not present. The previous value is passed to the eviction
+    /// listener with 'EvictionCause::Replaced' after the entry is unlocked. When 'f' returns
+    /// 'None' the entry is removed and its value is passed to the eviction listener with
+    /// 'EvictionCause::Removed'. Returns whether a value is stored afterwards.
+    pub fn compute<'a, M, F>(&'a self, method: M, key: &K, f: F) -> Result<bool, Error>
+    where
+        M: 'a + LockingMethod<'a, V>,
+        F: FnOnce(Option<&V>) -> Option<V>,
+    {
+        match self.entry(method, key)? {
+            CacheEntry::Occupied(mut guard) => match f(Some(&*guard)) {
+                Some(value) => {
+                    let old = std::mem::replace(&mut *guard, value);
+                    let bucket = guard.bucket;
+                    // the listener must not be called while the entry is locked
+                    drop(guard);
+                    bucket.notify_replaced(key, old);
+                    Ok(true)
+                }
+                None => {
+                    // since we hold the entry it is dropped with the guard
+                    guard
+                        .bucket
+                        .remove_entry(guard.entry, EvictionCause::Removed);
+                    Ok(false)
+                }
+            },
+            CacheEntry::Vacant(vacant) => Ok(f(None).map(|value| vacant.insert(value)).is_some()),
+        }
+    }
+
+    /// Removes the entry associated with key from the CacheDb. Entries that are not in use
+    /// are dropped immediately. Entries which are still locked are removed from the map, thus
+    /// can not be queried anymore, and are dropped when their last guard is released.
+    /// Returns 'true' when an entry was present.
+    pub fn remove<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (bucket, hash) = self.bucket(key);
+        bucket.lock_map().remove(hash, key, EvictionCause::Removed)
+    }
+
+    /// Removes the entry associated with key from the CacheDb and returns its value. This
+    /// acquires a write lock with the given 'method' first. Other threads waiting for the
+    /// lock will then fail with 'Error::NoEntry'. Returns 'None' when there is no entry or
+    /// the lock could not be obtained. Since the value is handed to the caller the eviction
+    /// listener is not called.
+    pub fn take<'a, M, Q>(&'a self, method: M, key: &Q) -> Option<V>
+    where
+        M: 'a + LockingMethod<'a, V>,
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let mut guard = self.get_mut(method, key).ok()?;
+        let value = guard.guard.take();
+        // since we hold the entry it will never be dropped here
+        guard
+            .bucket
+            .remove_entry(guard.entry, EvictionCause::Removed);
+        drop(guard);
+        value
+    }
+
+    /// Marks the entry associated with key for expiration without locking it. An unused entry
+    /// is moved to the front of the LRU list and will be evicted next. An entry in use is put
+    /// there when released, unless it becomes queried again in the meantime.  Returns 'true'
+    /// when an entry was present.
+    pub fn expire<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (bucket, hash) = self.bucket(key);
+        let map_lock = bucket.lock_map();
+
+        if let Some(entry) = map_lock.get(hash, key) {
+            bucket.expire_entry(entry);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns the statistics of all buckets and their sum.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats::new(self.buckets.iter().map(Bucket::stats).collect())
+    }
+
     /// Disable the LRU eviction. Can be called multiple times, every call should be paired
     /// with a 'enable_lru()' call to reenable the LRU finally. Failing to do so may keep the
     /// CacheDb filling up forever. However this might be intentional to disable the LRU
     /// expiration entirely.
     pub fn disable_lru_eviction(&self) -> &Self {
-        self.lru_disabled.fetch_add(1, Ordering::Relaxed);
+        for bucket in &self.buckets {
+            bucket.lru_disabled.fetch_add(1, Ordering::Relaxed);
+        }
         self
     }
 
     /// Re-Enables the LRU eviction after it was disabled. every call must be preceeded by a call to
     /// 'disable_lru()'. Calling it without an matching 'disable_lru()' will panic with an integer underflow.
     pub fn enable_lru_eviction(&self) -> &Self {
-        self.lru_disabled.fetch_sub(1, Ordering::Relaxed);
+        for bucket in &self.buckets {
+            bucket.lru_disabled.fetch_sub(1, Ordering::Relaxed);
+        }
         self
     }
 
@@ -348,8 +805,15 @@ where
     /// condition when other threads access the CacheDb at the same time but may make sense
     /// when lru_eviction is disabled and it can be ensure that no other thread inserts the
     /// key.
-    pub fn contains_key(&self, key: &K) -> bool {
-        self.buckets[key.bucket::<N>()].lock_map().contains(key)
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (bucket, hash) = self.bucket(key);
+        bucket
+            .get_entry(&mut bucket.lock_map(), hash, key)
+            .is_some()
     }
 
     /// The 'cache_target' will only recalculated after this many inserts. Should be in the
@@ -368,10 +832,10 @@ where
     /// 'max_capacity_limit'.
     pub fn config_min_capacity_limit(&self, min_capacity_limit: usize) -> &Self {
         for bucket in &self.buckets {
-            // divide by N so that each bucket gets its share
+            // divide by the number of buckets so that each bucket gets its share
             bucket
                 .min_capacity_limit
-                .store(min_capacity_limit / N, Ordering::Relaxed);
+                .store(min_capacity_limit / self.buckets.len(), Ordering::Relaxed);
         }
         self
     }
@@ -380,10 +844,10 @@ where
     /// Should be fine around the maximum expected number of entries.
     pub fn config_max_capacity_limit(&self, max_capacity_limit: usize) -> &Self {
         for bucket in &self.buckets {
-            // divide by N so that each bucket gets its share
+            // divide by the number of buckets so that each bucket gets its share
             bucket
                 .max_capacity_limit
-                .store(max_capacity_limit / N, Ordering::Relaxed);
+                .store(max_capacity_limit / self.buckets.len(), Ordering::Relaxed);
         }
         self
     }
@@ -417,6 +881,75 @@ where
         self
     }
 
+    /// Sets the time to live for entries that are constructed without an explicit one. Once
+    /// it passed, entries are treated as missing and reclaimed. 'None' (the default) keeps
+    /// entries until they are evicted by the LRU.
+    pub fn config_default_ttl(&self, default_ttl: Option<Duration>) -> &Self {
+        for bucket in &self.buckets {
+            bucket
+                .default_ttl
+                .store(entry::nanos(default_ttl), Ordering::Relaxed);
+        }
+        self
+    }
+
+    /// Sets the time after which unused entries are evicted, regardless of the 'cache_target'.
+    /// Idle entries are treated as missing and reclaimed on the next insert. Policies whose
+    /// lists are not ordered by release time ('WTinyLfu') search up to 'evict_batch' entries
+    /// deep for idle entries, the remaining ones are reclaimed when looked up. 'None' (the
+    /// default) disables the idle timeout.
+    pub fn config_time_to_idle(&self, time_to_idle: Option<Duration>) -> &Self {
+        for bucket in &self.buckets {
+            bucket
+                .time_to_idle
+                .store(entry::nanos(time_to_idle), Ordering::Relaxed);
+        }
+        self
+    }
+
+    /// Sets the function that computes the weight of entries. It is called whenever an entry
+    /// got constructed and when a write guard is released. Without a weigher every entry
+    /// weighs 1.
+    pub fn config_weigher<W>(&self, weigher: W) -> &Self
+    where
+        W: Fn(&K, &V) -> usize + Send + Sync + 'static,
+    {
+        let weigher: Arc<bucket::Weigher<K, V>> = Arc::new(weigher);
+        for bucket in &self.buckets {
+            *bucket.weigher.write() = Some(weigher.clone());
+        }
+        self
+    }
+
+    /// Sets the total weight all entries may have. When exceeded, entries are evicted from
+    /// the LRU until the weight is within the budget again. Locked entries can not be evicted,
+    /// thus the weight may stay above when too much entries are in use.
+    pub fn config_max_weight(&self, max_weight: usize) -> &Self {
+        for bucket in &self.buckets {
+            // divide by the number of buckets so that each bucket gets its share
+            bucket
+                .max_weight
+                .store(max_weight / self.buckets.len(), Ordering::Relaxed);
+        }
+        self
+    }
+
+    /// Registers a listener that is called with the key and value of every entry that is
+    /// dropped from the CacheDb, along with the cause. Entries in use are passed to the
+    /// listener when their last guard is released. The listener is never called while the
+    /// map of a bucket is locked, but it may be called while the caller holds a lock on some
+    /// other entry. Entries dropped together with the CacheDb are not passed to the listener.
+    pub fn on_evict<F>(&self, listener: F) -> &Self
+    where
+        F: Fn(K, V, EvictionCause) + Send + Sync + 'static,
+    {
+        let listener: Arc<bucket::EvictionListener<K, V>> = Arc::new(listener);
+        for bucket in &self.buckets {
+            *bucket.listener.write() = Some(listener.clone());
+        }
+        self
+    }
+
     /// Sets the number of entries removed at once when evicting entries from the cache. Since
     /// evicting branches into the code parts for removing the entries and calling their
     /// destructors it is a bit more cache friendly to batch a few such things together.
@@ -427,15 +960,16 @@ where
         self
     }
 
-    /// Evicts up to number entries. The implementation is pretty simple trying to evict number/N from
+    /// Evicts up to number entries. The implementation is pretty simple trying to evict an equal share from
     /// each bucket. Thus when the distribution is not optimal fewer elements will be removed.
     /// Will not remove any entries when the lru eviction is disabled.
     /// Returns the number of items that got evicted.
     pub fn evict(&self, number: usize) -> usize {
-        if self.lru_disabled.load(Ordering::Relaxed) == 0 {
+        // all buckets are disabled together
+        if !self.buckets[0].eviction_disabled() {
             let mut evicted = number;
             for bucket in &self.buckets {
-                evicted -= bucket.evict(number / N, &mut bucket.lock_map());
+                evicted -= bucket.evict(number / self.buckets.len(), &mut bucket.lock_map());
             }
             evicted
         } else {
@@ -444,38 +978,139 @@ where
     }
 }
 
-impl<K, V, const N: usize> Default for CacheDb<K, V, N>
+/// The async API, enabled by the 'async' feature. Waiting for entry locks suspends the task
+/// instead of blocking the thread.
+#[cfg(feature = "async")]
+impl<K, V, P, S> CacheDb<K, V, P, S>
 where
     K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+    S: BuildHasher,
 {
-    fn default() -> Self {
-        Self::new()
+    /// Query the Entry associated with key for reading. The 'method' can be one of
+    /// 'Blocking', 'TryLock', 'Duration' or 'Instant' with the same meaning as in 'get()'.
+    pub async fn get_async<'a, M, Q>(
+        &'a self,
+        method: M,
+        key: &Q,
+    ) -> Result<EntryReadGuard<'a, K, V, P>, Error>
+    where
+        M: AsyncLockingMethod,
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        // Safety: the entry is in use and thus can not be dropped
+        let (bucket, entry) = self
+            .query_entry(key)
+            .map(|(bucket, entry)| (bucket, unsafe { &*entry }))?;
+        EntryReadGuard::lock_async(bucket, entry, &method).await
     }
-}
-
-/// Result type that boxes the error. Allows constructors to return arbitary errors.
-pub type DynResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
-
-/// The errors that CacheDb implements itself. Note that the constructors can return other
-/// errors as well ('DynResult' is returned in those case).
-#[derive(Debug)]
-pub enum Error {
-    /// The Entry was not found
-    NoEntry,
-    /// Locking an entry failed
-    LockUnavailable,
-}
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Error::NoEntry => write!(f, "Entry not found"),
-            Error::LockUnavailable => write!(f, "Trying to lock failed"),
-        }
+    /// Query the Entry associated with key for writing.
+    pub async fn get_mut_async<'a, M, Q>(
+        &'a self,
+        method: M,
+        key: &Q,
+    ) -> Result<EntryWriteGuard<'a, K, V, P>, Error>
+    where
+        M: AsyncLockingMethod,
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        // Safety: the entry is in use and thus can not be dropped
+        let (bucket, entry) = self
+            .query_entry(key)
+            .map(|(bucket, entry)| (bucket, unsafe { &*entry }))?;
+        EntryWriteGuard::lock_async(bucket, entry, &method).await
     }
-}
-
-impl std::error::Error for Error {}
+
+    /// Query an Entry for reading or construct it (atomically) with an async constructor.
+    /// Only one constructor runs for a key at a time, concurrent callers await its result
+    /// instead of constructing the value again. When the future constructing the value is
+    /// dropped, the entry is removed and one of the waiting callers constructs it instead.
+    pub async fn get_or_insert_async<'a, M, F, Fut>(
+        &'a self,
+        method: M,
+        key: &K,
+        ctor: F,
+    ) -> DynResult<EntryReadGuard<'a, K, V, P>>
+    where
+        F: FnOnce(&K) -> Fut,
+        Fut: std::future::Future<Output = DynResult<V>>,
+        M: AsyncLockingMethod,
+    {
+        loop {
+            // No raw pointers may be held across an await, the future would not be 'Send'.
+            let query = match self.query_or_insert_entry(key) {
+                // Safety: the entry is in use and thus can not be dropped
+                Ok((bucket, entry_ptr)) => Ok((bucket, unsafe { &*entry_ptr })),
+                Err((bucket, entry_ptr, map_lock)) => {
+                    Err(self.new_placeholder(bucket, entry_ptr, map_lock, None))
+                }
+            };
+            match query {
+                Ok((bucket, entry)) => {
+                    match EntryReadGuard::lock_async(bucket, entry, &method).await {
+                        // the entry was removed while we waited for it, try again
+                        Err(Error::NoEntry) => continue,
+                        result => return Ok(result?),
+                    }
+                }
+                Err(placeholder) => {
+                    return Ok(placeholder.construct_async(ctor).await?.downgrade());
+                }
+            }
+        }
+    }
+}
+
+impl<K, V, P, S> std::fmt::Debug for CacheDb<K, V, P, S>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+    S: BuildHasher,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CacheDb")
+            .field("buckets", &self.buckets)
+            .finish()
+    }
+}
+
+impl<K, V, P, S> Default for CacheDb<K, V, P, S>
+where
+    K: KeyTraits,
+    P: EvictionPolicy<K, V>,
+    S: BuildHasher + Default,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Result type that boxes the error. Allows constructors to return arbitary errors.
+pub type DynResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// The errors that CacheDb implements itself. Note that the constructors can return other
+/// errors as well ('DynResult' is returned in those case).
+#[derive(Debug)]
+pub enum Error {
+    /// The Entry was not found
+    NoEntry,
+    /// Locking an entry failed
+    LockUnavailable,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NoEntry => write!(f, "Entry not found"),
+            Error::LockUnavailable => write!(f, "Trying to lock failed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
 
 #[cfg(test)]
 mod test {
@@ -483,6 +1118,9 @@ mod test {
     use std::env;
     use std::sync::{Arc, Barrier};
     use std::{thread, time};
+    #[cfg(feature = "async")]
+    use std::sync::atomic::AtomicU32;
+    #[cfg(feature = "logging")]
     use std::sync::atomic::AtomicU64;
     #[cfg(feature = "logging")]
     use std::io::Write;
@@ -516,33 +1154,86 @@ mod test {
     #[cfg(not(feature = "logging"))]
     fn init() {}
 
-    // using the default hash based implementation for tests here
-    impl Bucketize for String {}
-    impl Bucketize for u16 {
-        fn bucket<const N: usize>(&self) -> usize {
-            let r = *self as usize % N;
-            #[cfg(feature = "logging")]
-            trace!("key {} falls into bucket {}", self, r);
-            r
+    impl KeyTraits for u16 {}
+
+    /// Uses integer keys as their hash, like fast hashers for trusted keys do. Makes tests
+    /// depending on the distribution deterministic.
+    #[derive(Default, Clone)]
+    struct IdentityState;
+
+    struct IdentityHasher(u64);
+
+    impl BuildHasher for IdentityState {
+        type Hasher = IdentityHasher;
+
+        fn build_hasher(&self) -> IdentityHasher {
+            IdentityHasher(0)
         }
     }
 
-    impl KeyTraits for String {}
-    impl KeyTraits for u16 {}
+    impl std::hash::Hasher for IdentityHasher {
+        fn finish(&self) -> u64 {
+            self.0
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            for byte in bytes {
+                self.0 = self.0 << 8 | *byte as u64;
+            }
+        }
+
+        fn write_u16(&mut self, key: u16) {
+            self.0 = key as u64;
+        }
+    }
+
+    type IdentityDb = CacheDb<u16, u16, Lru<u16, u16>, IdentityState>;
+
+    /// Returns the index of the bucket 'key' falls into.
+    fn bucket_of(cdb: &IdentityDb, key: u16) -> usize {
+        cdb.bucket_index(cdb.hasher.hash_one(key))
+    }
 
     #[test]
     fn create() {
         init();
-        let cdb = CacheDb::<String, String, 16>::new();
+        let cdb = CacheDb::<String, String>::with_buckets(16);
 
-        println!("Debug {:?}", &cdb);
+        println!("Debug {:?}", cdb);
         assert!(cdb.get(Blocking, &"foo".to_string()).is_err());
     }
 
+    #[test]
+    fn bucket_count() {
+        init();
+        let cdb = CacheDb::<String, String>::new();
+        let cpus = thread::available_parallelism().unwrap().get();
+        assert_eq!(cdb.stats().buckets.len(), cpus * 4);
+
+        let cdb = IdentityDb::with_buckets(8);
+        let mut lens = vec![0; 8];
+        for key in 0..1000 {
+            cdb.insert(&key, |key| Ok(*key)).unwrap();
+            lens[bucket_of(&cdb, key)] += 1;
+        }
+        let stats = cdb.stats();
+        assert_eq!(stats.buckets.len(), 8);
+        assert_eq!(
+            stats
+                .buckets
+                .iter()
+                .map(|stats| stats.len)
+                .collect::<Vec<_>>(),
+            lens
+        );
+        // identity hashes of small keys are spread over all buckets
+        assert!(lens.iter().all(|len| *len > 1000 / 8 / 2));
+    }
+
     #[test]
     fn insert_foobar_onebucket() {
         init();
-        let cdb = CacheDb::<String, String, 1>::new();
+        let cdb = CacheDb::<String, String>::with_buckets(1);
 
         assert!(
             cdb.get_or_insert(Blocking, &"foo".to_string(), |_| Ok("bar".to_string()))
@@ -581,7 +1272,7 @@ mod test {
     #[test]
     fn insert_foobar() {
         init();
-        let cdb = CacheDb::<String, String, 16>::new();
+        let cdb = CacheDb::<String, String>::with_buckets(16);
 
         assert!(
             cdb.get_or_insert(Blocking, &"foo".to_string(), |_| Ok("bar".to_string()))
@@ -620,7 +1311,7 @@ mod test {
     #[test]
     fn insert_unit() {
         init();
-        let cdb = CacheDb::<String, (), 16>::new();
+        let cdb = CacheDb::<String, ()>::with_buckets(16);
 
         assert!(cdb.insert(&"foo".to_string(), |_| Ok(())).is_ok());
         assert_eq!(*cdb.get(Blocking, &"foo".to_string()).unwrap(), ());
@@ -628,15 +1319,15 @@ mod test {
         assert!(cdb.insert(&"bar".to_string(), |_| Ok(())).is_ok());
         assert_eq!(*cdb.get(Blocking, &"bar".to_string()).unwrap(), ());
 
-        assert_eq!(cdb.contains_key(&"foo".to_string()), true);
-        assert_eq!(cdb.contains_key(&"bar".to_string()), true);
-        assert_eq!(cdb.contains_key(&"baz".to_string()), false);
+        assert!(cdb.contains_key(&"foo".to_string()));
+        assert!(cdb.contains_key(&"bar".to_string()));
+        assert!(!cdb.contains_key(&"baz".to_string()));
     }
 
     #[test]
     fn trylocks() {
         init();
-        let cdb = CacheDb::<String, String, 16>::new();
+        let cdb = CacheDb::<String, String>::with_buckets(16);
 
         assert!(
             cdb.get_or_insert(Blocking, &"foo".to_string(), |_| Ok("bar".to_string()))
@@ -664,7 +1355,7 @@ mod test {
     #[test]
     fn recursivelocks() {
         init();
-        let cdb = CacheDb::<String, String, 16>::new();
+        let cdb = CacheDb::<String, String>::with_buckets(16);
 
         assert!(
             cdb.get_or_insert(Blocking, &"foo".to_string(), |_| Ok("bar".to_string()))
@@ -694,7 +1385,7 @@ mod test {
     #[test]
     fn mutate() {
         init();
-        let cdb = CacheDb::<String, String, 16>::new();
+        let cdb = CacheDb::<String, String>::with_buckets(16);
 
         cdb.get_or_insert(Blocking, &"foo".to_string(), |_| Ok("bar".to_string()))
             .unwrap();
@@ -709,7 +1400,7 @@ mod test {
     #[test]
     fn insert_mutate() {
         init();
-        let cdb = CacheDb::<String, String, 16>::new();
+        let cdb = CacheDb::<String, String>::with_buckets(16);
 
         let mut foo = cdb
             .get_or_insert_mut(Blocking, &"foo".to_string(), |_| Ok("bar".to_string()))
@@ -724,11 +1415,1250 @@ mod test {
         );
     }
 
+    #[test]
+    fn borrowed_key() {
+        init();
+        let cdb = CacheDb::<String, String>::with_buckets(16);
+
+        cdb.get_or_insert(Blocking, &"foo".to_string(), |_| Ok("bar".to_string()))
+            .unwrap();
+        assert!(cdb.contains_key("foo"));
+        assert_eq!(*cdb.get(Blocking, "foo").unwrap(), "bar".to_string());
+        cdb.get_mut(Blocking, "foo").unwrap().push('!');
+        assert_eq!(*cdb.get(Blocking, "foo").unwrap(), "bar!".to_string());
+        assert!(cdb.expire("foo"));
+        assert_eq!(cdb.take(Blocking, "foo"), Some("bar!".to_string()));
+        assert!(!cdb.remove("foo"));
+        assert!(cdb.get(Blocking, "foo").is_err());
+    }
+
+    #[test]
+    fn remove() {
+        init();
+        let cdb = CacheDb::<String, String>::with_buckets(16);
+
+        cdb.get_or_insert(Blocking, &"foo".to_string(), |_| Ok("bar".to_string()))
+            .unwrap();
+        assert!(cdb.remove(&"foo".to_string()));
+        assert!(!cdb.remove(&"foo".to_string()));
+        assert!(!cdb.contains_key(&"foo".to_string()));
+        assert!(cdb.get(Blocking, &"foo".to_string()).is_err());
+    }
+
+    #[test]
+    fn remove_locked() {
+        init();
+        let cdb = CacheDb::<String, String>::with_buckets(16);
+
+        let foo = cdb
+            .get_or_insert(Blocking, &"foo".to_string(), |_| Ok("bar".to_string()))
+            .unwrap();
+        assert!(cdb.remove(&"foo".to_string()));
+        assert!(!cdb.contains_key(&"foo".to_string()));
+
+        // a new entry can be created while the old one is still locked
+        let foo2 = cdb
+            .get_or_insert(Blocking, &"foo".to_string(), |_| Ok("baz".to_string()))
+            .unwrap();
+        assert_eq!(*foo, "bar".to_string());
+        assert_eq!(*foo2, "baz".to_string());
+        drop(foo);
+        drop(foo2);
+        assert_eq!(
+            *cdb.get(Blocking, &"foo".to_string()).unwrap(),
+            "baz".to_string()
+        );
+    }
+
+    #[test]
+    fn take() {
+        init();
+        let cdb = CacheDb::<String, String>::with_buckets(16);
+
+        cdb.get_or_insert(Blocking, &"foo".to_string(), |_| Ok("bar".to_string()))
+            .unwrap();
+        let foo = cdb.get(Blocking, &"foo".to_string()).unwrap();
+        assert_eq!(cdb.take(TryLock, &"foo".to_string()), None);
+        drop(foo);
+        assert_eq!(
+            cdb.take(TryLock, &"foo".to_string()),
+            Some("bar".to_string())
+        );
+        assert_eq!(cdb.take(Blocking, &"foo".to_string()), None);
+        assert!(!cdb.contains_key(&"foo".to_string()));
+    }
+
+    #[test]
+    fn take_waiting() {
+        init();
+        let cdb = Arc::new(CacheDb::<String, String>::with_buckets(16));
+
+        let foo = cdb
+            .get_or_insert_mut(Blocking, &"foo".to_string(), |_| Ok("bar".to_string()))
+            .unwrap();
+
+        let taker = {
+            let cdb = Arc::clone(&cdb);
+            thread::spawn(move || cdb.take(Blocking, &"foo".to_string()))
+        };
+        thread::sleep(Duration::from_millis(50));
+        drop(foo);
+        assert_eq!(taker.join().unwrap(), Some("bar".to_string()));
+        assert!(cdb.get(Blocking, &"foo".to_string()).is_err());
+    }
+
+    #[test]
+    fn insert_existing() {
+        init();
+        let cdb = CacheDb::<String, String>::with_buckets(1);
+
+        assert!(
+            cdb.insert(&"foo".to_string(), |_| Ok("bar".to_string()))
+                .unwrap()
+        );
+        assert!(
+            !cdb.insert(&"foo".to_string(), |_| Ok("baz".to_string()))
+                .unwrap()
+        );
+        // the entry must not stay in use
+        assert_eq!(cdb.buckets[0].cached.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            *cdb.get(Blocking, &"foo".to_string()).unwrap(),
+            "bar".to_string()
+        );
+    }
+
+    #[test]
+    fn entry_api() {
+        init();
+        let cdb = CacheDb::<String, String>::with_buckets(1);
+        let foo = "foo".to_string();
+
+        match cdb.entry(Blocking, &foo).unwrap() {
+            CacheEntry::Vacant(vacant) => {
+                assert_eq!(vacant.key(), &foo);
+                assert!(cdb.get(TryLock, &foo).is_err());
+                vacant.insert("bar".to_string());
+            }
+            CacheEntry::Occupied(_) => panic!("entry must be vacant"),
+        }
+        match cdb.entry(Blocking, &foo).unwrap() {
+            CacheEntry::Occupied(mut guard) => guard.push('!'),
+            CacheEntry::Vacant(_) => panic!("entry must be occupied"),
+        }
+        assert_eq!(*cdb.get(Blocking, &foo).unwrap(), "bar!".to_string());
+
+        // vacant entries that are not filled leave nothing behind
+        let baz = "baz".to_string();
+        assert!(matches!(
+            cdb.entry(Blocking, &baz),
+            Ok(CacheEntry::Vacant(_))
+        ));
+        assert!(!cdb.contains_key(&baz));
+        if let Ok(CacheEntry::Vacant(vacant)) = cdb.entry(Blocking, &baz) {
+            assert!(vacant.insert_with(|_| Err("failed".into())).is_err());
+        }
+        assert!(!cdb.contains_key(&baz));
+        assert_eq!(cdb.buckets[0].lock_map().len(), 1);
+    }
+
+    #[test]
+    fn upsert_replace() {
+        init();
+        let cdb = CacheDb::<String, String>::with_buckets(1);
+        let replaced = Arc::new(std::sync::Mutex::new(Vec::new()));
+        {
+            let replaced = Arc::clone(&replaced);
+            cdb.on_evict(move |key, value, cause| {
+                replaced.lock().unwrap().push((key, value, cause))
+            });
+        }
+        let foo = "foo".to_string();
+
+        cdb.upsert(&foo, "bar".to_string());
+        cdb.upsert(&foo, "baz".to_string());
+        assert_eq!(*cdb.get(Blocking, &foo).unwrap(), "baz".to_string());
+        assert_eq!(*replaced.lock().unwrap(), vec![(
+            foo.clone(),
+            "bar".to_string(),
+            EvictionCause::Replaced
+        )]);
+
+        assert_eq!(
+            cdb.replace(Blocking, &foo, "qux".to_string()).unwrap(),
+            Some("baz".to_string())
+        );
+        assert_eq!(
+            cdb.replace(Blocking, &"new".to_string(), "value".to_string())
+                .unwrap(),
+            None
+        );
+        {
+            let _guard = cdb.get(Blocking, &foo).unwrap();
+            assert!(matches!(
+                cdb.replace(TryLock, &foo, "locked".to_string()),
+                Err(Error::LockUnavailable)
+            ));
+        }
+        assert_eq!(*cdb.get(Blocking, &foo).unwrap(), "qux".to_string());
+        assert_eq!(*cdb.get(Blocking, "new").unwrap(), "value".to_string());
+        assert_eq!(replaced.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn compute() {
+        init();
+        let cdb = CacheDb::<u16, u16>::with_buckets(1);
+        let removed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        {
+            let removed = Arc::clone(&removed);
+            cdb.on_evict(move |key, value, cause| {
+                removed.lock().unwrap().push((key, value, cause))
+            });
+        }
+
+        // absent keys stay absent
+        assert!(!cdb.compute(Blocking, &1, |_| None).unwrap());
+        assert!(!cdb.contains_key(&1));
+        assert_eq!(cdb.stats().total.ctor_failures, 0);
+
+        for expected in 1..=3 {
+            assert!(
+                cdb.compute(Blocking, &1, |value| {
+                    Some(value.map_or(1, |value| value + 1))
+                })
+                .unwrap()
+            );
+            assert_eq!(*cdb.get(TryLock, &1).unwrap(), expected);
+        }
+
+        assert!(!cdb.compute(Blocking, &1, |_| None).unwrap());
+        assert!(!cdb.contains_key(&1));
+        assert_eq!(*removed.lock().unwrap(), vec![
+            (1, 1, EvictionCause::Replaced),
+            (1, 2, EvictionCause::Replaced),
+            (1, 3, EvictionCause::Removed),
+        ]);
+    }
+
+    #[test]
+    fn mapped_guards() {
+        init();
+        let cdb = CacheDb::<u16, (u16, Vec<u16>)>::with_buckets(1);
+        cdb.config_weigher(|_, value| value.1.len());
+        cdb.insert(&1, |_| Ok((1, vec![1]))).unwrap();
+
+        let guard = cdb.get(Blocking, &1).unwrap();
+        let guard = match EntryReadGuard::try_map(guard, |value| value.1.get(5)) {
+            Err(guard) => guard,
+            Ok(_) => panic!("there is no such element"),
+        };
+        let first = EntryReadGuard::map(guard, |value| &value.1);
+        let first = MappedEntryReadGuard::map(first, |list| &list[0]);
+        assert_eq!(*first, 1);
+        // the entry stays in use
+        assert!(cdb.get(TryLock, &1).is_ok());
+        assert_eq!(cdb.buckets[0].cached.load(Ordering::Relaxed), 0);
+        drop(first);
+        assert_eq!(cdb.buckets[0].cached.load(Ordering::Relaxed), 1);
+
+        let mut list =
+            EntryWriteGuard::map(cdb.get_mut(Blocking, &1).unwrap(), |value| &mut value.1);
+        assert!(cdb.get(TryLock, &1).is_err());
+        list.extend([2, 3]);
+        drop(list);
+        assert_eq!(*cdb.get(Blocking, &1).unwrap(), (1, vec![1, 2, 3]));
+        assert_eq!(cdb.buckets[0].weight.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn upgradable() {
+        init();
+        let cdb = CacheDb::<u16, Vec<u16>>::with_buckets(1);
+        cdb.config_weigher(|_, value| value.len());
+        cdb.insert(&1, |_| Ok(vec![1])).unwrap();
+
+        let upgradable = cdb.get_upgradable(Blocking, &1).unwrap();
+        // readers may proceed, another upgradable lock may not
+        let reader = cdb.get(TryLock, &1).unwrap();
+        assert!(cdb.get_upgradable(TryLock, &1).is_err());
+        assert!(cdb.get_mut(TryLock, &1).is_err());
+        assert_eq!(*upgradable, vec![1]);
+
+        // the reader blocks the upgrade, the guard is given back
+        let upgradable = match upgradable.upgrade(TryLock) {
+            Err(upgradable) => upgradable,
+            Ok(_) => panic!("upgrade must fail while a reader is active"),
+        };
+        assert_eq!(cdb.stats().total.lock_timeouts, 3);
+        drop(reader);
+
+        let mut writer = upgradable.upgrade(Duration::from_millis(10)).ok().unwrap();
+        assert!(cdb.get(TryLock, &1).is_err());
+        writer.push(2);
+
+        let upgradable = writer.downgrade_to_upgradable();
+        assert_eq!(cdb.buckets[0].weight.load(Ordering::Relaxed), 2);
+        assert!(cdb.get(TryLock, &1).is_ok());
+        let reader = upgradable.downgrade();
+        assert!(cdb.get_upgradable(TryLock, &1).is_ok());
+        drop(reader);
+
+        let mut writer = cdb.get_mut(Blocking, &1).unwrap();
+        writer.push(3);
+        let reader = writer.downgrade();
+        assert_eq!(cdb.buckets[0].weight.load(Ordering::Relaxed), 3);
+        assert!(cdb.get(TryLock, &1).is_ok());
+        assert_eq!(*reader, vec![1, 2, 3]);
+        drop(reader);
+        assert_eq!(cdb.buckets[0].cached.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn owned_guards() {
+        init();
+        struct Holder {
+            name: OwnedEntryReadGuard<String, String>,
+        }
+
+        fn lookup(cdb: &Arc<CacheDb<String, String>>, key: &str) -> Holder {
+            Holder {
+                name: cdb
+                    .get_or_insert_owned(Blocking, &key.to_string(), |key| Ok(key.to_uppercase()))
+                    .unwrap(),
+            }
+        }
+
+        let cdb = Arc::new(CacheDb::<String, String>::with_buckets(1));
+        let holder = lookup(&cdb, "foo");
+        assert_eq!(*holder.name, "FOO");
+        assert!(cdb.get_mut(TryLock, "foo").is_err());
+        assert_eq!(cdb.buckets[0].cached.load(Ordering::Relaxed), 0);
+
+        // the guard keeps the cache alive
+        let weak = Arc::downgrade(&cdb);
+        drop(cdb);
+        assert_eq!(*holder.name, "FOO");
+        let cdb = holder.name.cache().clone();
+        drop(holder);
+        assert_eq!(cdb.buckets[0].cached.load(Ordering::Relaxed), 1);
+
+        let mut guard = cdb.get_mut_owned(Blocking, "foo").unwrap();
+        guard.push_str("BAR");
+        let guard = guard.downgrade();
+        assert_eq!(*cdb.get(TryLock, "foo").unwrap(), "FOOBAR");
+        drop(cdb);
+        drop(guard);
+        assert!(weak.upgrade().is_none());
+
+        let cdb = Arc::new(CacheDb::<String, String>::with_buckets(1));
+        let mut guard = cdb
+            .get_or_insert_mut_owned(Blocking, &"bar".to_string(), |_| Ok(String::new()))
+            .unwrap();
+        guard.push_str("baz");
+        drop(guard);
+        #[cfg(feature = "async")]
+        {
+            // with parking_lot's 'send_guard' guards can be moved to other threads
+            let guard = cdb.get_owned(Blocking, "bar").unwrap();
+            thread::spawn(move || assert_eq!(*guard, "baz"))
+                .join()
+                .unwrap();
+        }
+        assert_eq!(*cdb.get_owned(Blocking, "bar").unwrap(), "baz");
+        assert_eq!(cdb.buckets[0].cached.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn entry_handle() {
+        init();
+        let cdb = CacheDb::<u16, u16>::with_buckets(1);
+        cdb.insert(&1, |_| Ok(1)).unwrap();
+        cdb.insert(&2, |_| Ok(2)).unwrap();
+
+        let handle = cdb.handle(&1).unwrap();
+        assert_eq!(*handle.key(), 1);
+        assert_eq!(cdb.stats().total.hits, 1);
+        for _ in 0..10 {
+            *handle.write(Blocking).unwrap() += 1;
+            assert!(handle.write(TryLock).is_ok());
+        }
+        let reader = handle.read(Blocking).unwrap();
+        assert!(handle.write(TryLock).is_err());
+        assert_eq!(*handle.read(TryLock).unwrap(), 11);
+        drop(reader);
+        // no lookups were made
+        assert_eq!(cdb.stats().total.hits, 1);
+
+        // locking and unlocking through the handle does not touch the policy, the policy
+        // mutex is held by another thread until the handle was used
+        let barrier = std::sync::Barrier::new(2);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let _policy = cdb.buckets[0].policy.lock();
+                barrier.wait();
+                barrier.wait();
+            });
+            barrier.wait();
+            for _ in 0..10 {
+                drop(handle.read(Blocking).unwrap());
+                drop(handle.write(Blocking).unwrap());
+            }
+            assert!(cdb.buckets[0].policy.try_lock().is_none());
+            barrier.wait();
+        });
+
+        // handled entries stay in use and are not evicted
+        assert_eq!(cdb.buckets[0].cached.load(Ordering::Relaxed), 1);
+        cdb.evict(2);
+        assert_eq!(cdb.stats().total.len, 1);
+        assert_eq!(cdb.buckets[0].cached.load(Ordering::Relaxed), 0);
+        let clone = handle.clone();
+        drop(handle);
+        assert_eq!(*clone.read(Blocking).unwrap(), 11);
+        drop(clone);
+        assert_eq!(cdb.buckets[0].cached.load(Ordering::Relaxed), 1);
+
+        // removed entries can not be locked anymore
+        let handle = cdb.handle(&1).unwrap();
+        assert!(cdb.remove(&1));
+        assert!(handle.is_removed());
+        assert!(matches!(handle.read(Blocking), Err(Error::NoEntry)));
+        assert!(matches!(handle.write(Blocking), Err(Error::NoEntry)));
+        drop(handle);
+        assert!(cdb.handle(&1).is_err());
+        assert_eq!(cdb.buckets[0].cached.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn iteration() {
+        init();
+        let cdb = CacheDb::<u16, u16>::with_buckets(4);
+        for key in 0..100 {
+            cdb.insert(&key, |key| Ok(key * 2)).unwrap();
+        }
+
+        let mut keys: Vec<u16> = cdb.keys().collect();
+        keys.sort();
+        assert_eq!(keys, (0..100).collect::<Vec<_>>());
+        let mut keys = cdb.snapshot_keys();
+        keys.sort();
+        assert_eq!(keys, (0..100).collect::<Vec<_>>());
+
+        let mut sum = 0;
+        cdb.for_each(Blocking, |key, value| {
+            assert_eq!(*value, key * 2);
+            sum += *value as u32;
+        });
+        assert_eq!(sum, 9900);
+
+        // busy entries are skipped with TryLock, the CacheDb can be used while iterating
+        let busy = cdb.get_mut(Blocking, &7).unwrap();
+        let mut visited = 0;
+        cdb.for_each(TryLock, |key, _| {
+            assert_ne!(*key, 7);
+            if *key % 2 == 0 {
+                cdb.remove(key);
+            }
+            visited += 1;
+        });
+        assert_eq!(visited, 99);
+        drop(busy);
+        assert_eq!(cdb.snapshot_keys().len(), 50);
+        assert_eq!(cdb.stats().total.cached, 50);
+
+        // the buckets are unlocked while iterating over the keys
+        for key in cdb.keys() {
+            cdb.remove(&key);
+        }
+        assert_eq!(cdb.keys().count(), 0);
+    }
+
+    #[test]
+    fn for_each_keeps_policy_state() {
+        init();
+        let cdb = CacheDb::<String, u16>::with_buckets(1);
+        for key in 0..5 {
+            cdb.insert(&key.to_string(), |_| Ok(key)).unwrap();
+        }
+        drop(cdb.get(Blocking, &"0".to_string()));
+        cdb.for_each(Blocking, |_, _| {});
+        cdb.evict(1);
+        assert!(!cdb.contains_key(&"1".to_string()));
+        assert!(cdb.contains_key(&"0".to_string()));
+
+        let cdb = CacheDb::<u16, u16, AdaptiveReplacement<u16, u16>>::with_buckets(1);
+        for key in 0..10 {
+            cdb.insert(&key, |key| Ok(*key)).unwrap();
+        }
+        for key in 0..5 {
+            drop(cdb.get(Blocking, &key));
+        }
+        let segments = |cdb: &CacheDb<u16, u16, AdaptiveReplacement<u16, u16>>| {
+            let mut segments: Vec<(u16, u8)> = cdb.buckets[0]
+                .lock_map()
+                .iter()
+                .map(|entry| (entry.key, entry.segment()))
+                .collect();
+            segments.sort();
+            segments
+        };
+        let before = segments(&cdb);
+        cdb.for_each(Blocking, |_, _| {});
+        assert_eq!(segments(&cdb), before);
+        assert_eq!(cdb.stats().total.cached, 10);
+
+        // entries visited while being evicted stay in the CacheDb
+        let mut visited = 0;
+        cdb.for_each(Blocking, |key, _| {
+            if visited == 0 {
+                cdb.evict(10);
+                assert_eq!(cdb.keys().collect::<Vec<_>>(), [*key]);
+            }
+            visited += 1;
+        });
+        assert_eq!(visited, 1);
+        assert_eq!(cdb.stats().total.cached, 1);
+        cdb.evict(1);
+        assert_eq!(cdb.keys().count(), 0);
+        assert_eq!(cdb.stats().total.cached, 0);
+    }
+
+    #[test]
+    fn for_each_keeps_arc_ghosts() {
+        init();
+        let cdb = CacheDb::<u16, u16, AdaptiveReplacement<u16, u16>>::with_buckets(1);
+        for key in 0..10 {
+            cdb.insert(&key, |key| Ok(*key)).unwrap();
+        }
+        // 0 and 1 stay in the recency list and are the next victims
+        for key in 2..10 {
+            drop(cdb.get(Blocking, &key));
+        }
+        let segment = |key: u16| {
+            let map_lock = cdb.buckets[0].lock_map();
+            let entry = map_lock.iter().find(|entry| entry.key == key).unwrap();
+            entry.segment()
+        };
+        assert_eq!(segment(0), 0);
+
+        // 0 is selected as victim while visited, it must not become a ghost
+        cdb.for_each(Blocking, |key, _| {
+            if *key == 0 {
+                cdb.evict(1);
+            }
+        });
+        assert!(cdb.contains_key(&0));
+        assert!(!cdb.contains_key(&1));
+        assert_eq!(cdb.stats().total.cached, 9);
+
+        // inserting a ghost again is a hit in the ghost list, any other key is not
+        cdb.remove(&0);
+        cdb.insert(&0, |key| Ok(*key)).unwrap();
+        assert_eq!(segment(0), 0);
+        cdb.insert(&1, |key| Ok(*key)).unwrap();
+        assert_eq!(segment(1), 1);
+    }
+
+    #[test]
+    fn ctor_error() {
+        init();
+        let cdb = CacheDb::<String, String>::with_buckets(16);
+
+        assert!(
+            cdb.get_or_insert(Blocking, &"foo".to_string(), |_| Err("failed".into()))
+                .is_err()
+        );
+        assert!(!cdb.contains_key(&"foo".to_string()));
+        assert!(cdb.get(Blocking, &"foo".to_string()).is_err());
+
+        assert!(
+            cdb.get_or_insert_mut(Blocking, &"foo".to_string(), |_| Err("failed".into()))
+                .is_err()
+        );
+        assert!(!cdb.contains_key(&"foo".to_string()));
+
+        assert!(
+            cdb.insert(&"foo".to_string(), |_| Err("failed".into()))
+                .is_err()
+        );
+        assert!(!cdb.contains_key(&"foo".to_string()));
+
+        assert_eq!(
+            *cdb.get_or_insert(Blocking, &"foo".to_string(), |_| Ok("bar".to_string()))
+                .unwrap(),
+            "bar".to_string()
+        );
+    }
+
+    #[test]
+    fn ctor_panic() {
+        init();
+        let cdb = CacheDb::<String, String>::with_buckets(16);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            cdb.get_or_insert(Blocking, &"foo".to_string(), |_| panic!("ctor panic"))
+                .is_ok()
+        }));
+        assert!(result.is_err());
+        assert!(!cdb.contains_key(&"foo".to_string()));
+        assert!(cdb.get(TryLock, &"foo".to_string()).is_err());
+
+        assert_eq!(
+            *cdb.get_or_insert(TryLock, &"foo".to_string(), |_| Ok("bar".to_string()))
+                .unwrap(),
+            "bar".to_string()
+        );
+    }
+
+    #[test]
+    fn ctor_error_waiters() {
+        init();
+        let cdb = Arc::new(CacheDb::<String, String>::with_buckets(16));
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+
+        let failing = {
+            let cdb = Arc::clone(&cdb);
+            thread::spawn(move || {
+                cdb.get_or_insert(Blocking, &"foo".to_string(), |_| {
+                    started_tx.send(()).unwrap();
+                    thread::sleep(Duration::from_millis(100));
+                    Err("failed".into())
+                })
+                .is_err()
+            })
+        };
+        started_rx.recv().unwrap();
+
+        let getter = {
+            let cdb = Arc::clone(&cdb);
+            thread::spawn(move || {
+                matches!(cdb.get(Blocking, &"foo".to_string()), Err(Error::NoEntry))
+            })
+        };
+
+        let inserter = {
+            let cdb = Arc::clone(&cdb);
+            thread::spawn(move || {
+                cdb.get_or_insert(Blocking, &"foo".to_string(), |_| Ok("bar".to_string()))
+                    .map(|guard| guard.clone())
+                    .unwrap()
+            })
+        };
+
+        assert!(failing.join().unwrap());
+        assert!(getter.join().unwrap());
+        assert_eq!(inserter.join().unwrap(), "bar".to_string());
+        assert_eq!(
+            *cdb.get(Blocking, &"foo".to_string()).unwrap(),
+            "bar".to_string()
+        );
+    }
+
+    #[test]
+    fn expire() {
+        init();
+        let cdb = CacheDb::<String, String>::with_buckets(1);
+
+        for key in ["foo", "bar", "baz"] {
+            cdb.insert(&key.to_string(), |_| Ok(key.to_string()))
+                .unwrap();
+        }
+
+        assert!(cdb.expire(&"bar".to_string()));
+        assert!(!cdb.expire(&"none".to_string()));
+        cdb.evict(1);
+        assert!(!cdb.contains_key(&"bar".to_string()));
+
+        let mut baz = cdb.get(Blocking, &"baz".to_string()).unwrap();
+        baz.expire();
+        drop(baz);
+        cdb.evict(1);
+        assert!(!cdb.contains_key(&"baz".to_string()));
+        assert!(cdb.contains_key(&"foo".to_string()));
+    }
+
+    #[test]
+    fn time_to_live() {
+        init();
+        let cdb = CacheDb::<String, String>::with_buckets(16);
+
+        cdb.get_or_insert_with_ttl(
+            Blocking,
+            &"foo".to_string(),
+            Duration::from_millis(50),
+            |_| Ok("bar".to_string()),
+        )
+        .unwrap();
+        assert_eq!(
+            *cdb.get(Blocking, &"foo".to_string()).unwrap(),
+            "bar".to_string()
+        );
+
+        thread::sleep(Duration::from_millis(60));
+        assert!(cdb.get(Blocking, &"foo".to_string()).is_err());
+        assert!(!cdb.contains_key(&"foo".to_string()));
+
+        // rebuilt by the ctor
+        assert_eq!(
+            *cdb.get_or_insert(Blocking, &"foo".to_string(), |_| Ok("baz".to_string()))
+                .unwrap(),
+            "baz".to_string()
+        );
+    }
+
+    #[test]
+    fn default_ttl() {
+        init();
+        let cdb = CacheDb::<String, String>::with_buckets(1);
+        cdb.config_default_ttl(Some(Duration::from_millis(50)));
+
+        for key in ["foo", "bar", "baz"] {
+            cdb.insert(&key.to_string(), |_| Ok(key.to_string()))
+                .unwrap();
+        }
+        assert_eq!(cdb.buckets[0].lock_map().len(), 3);
+
+        thread::sleep(Duration::from_millis(60));
+        let foo = cdb
+            .get_or_insert(Blocking, &"foo".to_string(), |_| Ok("new".to_string()))
+            .unwrap();
+        assert_eq!(*foo, "new".to_string());
+
+        // the other expired entries got reclaimed by the insert
+        assert_eq!(cdb.buckets[0].lock_map().len(), 1);
+    }
+
+    #[test]
+    fn ttl_queue_bounded() {
+        init();
+        let cdb = CacheDb::<u16, u16>::with_buckets(1);
+        cdb.config_default_ttl(Some(Duration::from_secs(3600)));
+        let scheduled =
+            || -> usize { cdb.buckets[0].ttl_queue.lock().values().map(Vec::len).sum() };
+
+        for key in 0..100 {
+            cdb.insert(&key, |key| Ok(*key)).unwrap();
+        }
+        assert_eq!(scheduled(), 100);
+        cdb.evict(100);
+        assert_eq!(cdb.stats().total.len, 0);
+        assert_eq!(scheduled(), 0);
+
+        // rewriting entries replaces their items
+        for _ in 0..10 {
+            for key in 0..100 {
+                cdb.remove(&key);
+                cdb.insert(&key, |key| Ok(*key)).unwrap();
+            }
+        }
+        assert_eq!(scheduled(), 100);
+
+        // removed entries still in use leave the queue as well
+        let guard = cdb.get(Blocking, &0).unwrap();
+        assert!(cdb.remove(&0));
+        assert_eq!(scheduled(), 99);
+        drop(guard);
+    }
+
+    #[test]
+    fn time_to_idle() {
+        init();
+        let cdb = CacheDb::<String, String>::with_buckets(1);
+        cdb.config_time_to_idle(Some(Duration::from_millis(100)));
+
+        for key in ["foo", "bar", "baz"] {
+            cdb.insert(&key.to_string(), |_| Ok(key.to_string()))
+                .unwrap();
+        }
+
+        thread::sleep(Duration::from_millis(60));
+        drop(cdb.get(Blocking, &"foo".to_string()).unwrap());
+        let baz = cdb.get(Blocking, &"baz".to_string()).unwrap();
+        thread::sleep(Duration::from_millis(60));
+
+        // bar was idle for too long
+        assert!(cdb.get(Blocking, &"bar".to_string()).is_err());
+        assert!(cdb.contains_key(&"foo".to_string()));
+
+        // locked entries are never idle
+        thread::sleep(Duration::from_millis(60));
+        cdb.insert(&"new".to_string(), |_| Ok("new".to_string()))
+            .unwrap();
+        // foo got evicted by the insert
+        assert_eq!(cdb.buckets[0].lock_map().len(), 2);
+        assert!(!cdb.contains_key(&"foo".to_string()));
+        assert_eq!(*baz, "baz".to_string());
+        drop(baz);
+        assert!(cdb.contains_key(&"baz".to_string()));
+    }
+
+    /// Returns the keys left after key 0 became idle while all others were used recently.
+    fn idle_keys<P: EvictionPolicy<u16, u16>>() -> Vec<u16> {
+        let cdb = CacheDb::<u16, u16, P>::with_buckets(1);
+        for key in 0..6 {
+            cdb.insert(&key, |key| Ok(*key)).unwrap();
+        }
+        drop(cdb.get(Blocking, &0));
+        let guards: Vec<_> = (1..5).map(|key| cdb.get(Blocking, &key).unwrap()).collect();
+        thread::sleep(Duration::from_millis(60));
+
+        // With W-TinyLFU 5 enters the probation segment first, 0 is moved behind it when the
+        // protected segment overflows.
+        drop(cdb.get(Blocking, &5));
+        cdb.insert(&6, |key| Ok(*key)).unwrap();
+        drop(guards);
+
+        cdb.config_time_to_idle(Some(Duration::from_millis(50)));
+        cdb.insert(&100, |key| Ok(*key)).unwrap();
+        let mut keys: Vec<u16> = cdb.buckets[0]
+            .lock_map()
+            .iter()
+            .map(|entry| entry.key)
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn time_to_idle_behind_recent() {
+        init();
+        let expected = [1, 2, 3, 4, 5, 6, 100];
+        assert_eq!(idle_keys::<Lru<u16, u16>>(), expected);
+        assert_eq!(idle_keys::<WTinyLfu<u16, u16>>(), expected);
+        assert_eq!(idle_keys::<AdaptiveReplacement<u16, u16>>(), expected);
+    }
+
+    #[test]
+    fn max_weight() {
+        init();
+        let cdb = CacheDb::<String, String>::with_buckets(1);
+        cdb.config_weigher(|_, value: &String| value.len())
+            .config_max_weight(10);
+
+        for key in ["foo", "bar", "baz"] {
+            cdb.insert(&key.to_string(), |_| Ok(format!("{}!", key)))
+                .unwrap();
+        }
+        assert!(!cdb.contains_key(&"foo".to_string()));
+        assert!(cdb.contains_key(&"bar".to_string()));
+        assert!(cdb.contains_key(&"baz".to_string()));
+        assert_eq!(cdb.buckets[0].weight.load(Ordering::Relaxed), 8);
+
+        // growing an entry evicts others
+        *cdb.get_mut(Blocking, &"baz".to_string()).unwrap() = "0123456789".to_string();
+        assert!(!cdb.contains_key(&"bar".to_string()));
+        assert_eq!(cdb.buckets[0].weight.load(Ordering::Relaxed), 10);
+
+        assert!(cdb.remove(&"baz".to_string()));
+        assert_eq!(cdb.buckets[0].weight.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn max_weight_disabled_eviction() {
+        init();
+        let cdb = CacheDb::<String, String>::with_buckets(1);
+        cdb.disable_lru_eviction().config_max_weight(1);
+
+        cdb.insert(&"a".to_string(), |_| Ok("a".to_string()))
+            .unwrap();
+        cdb.insert(&"b".to_string(), |_| Ok("b".to_string()))
+            .unwrap();
+        *cdb.get_mut(Blocking, "b").unwrap() = "bb".to_string();
+        drop(cdb.get_mut(Blocking, "a").unwrap().downgrade());
+        assert!(cdb.contains_key("a"));
+        assert!(cdb.contains_key("b"));
+        assert_eq!(cdb.evict(1), 0);
+
+        // once enabled again the budget applies
+        cdb.enable_lru_eviction();
+        cdb.insert(&"c".to_string(), |_| Ok("c".to_string()))
+            .unwrap();
+        assert_eq!(cdb.buckets[0].weight.load(Ordering::Relaxed), 1);
+        assert!(cdb.contains_key("c"));
+    }
+
+    #[test]
+    fn eviction_listener() {
+        init();
+        let cdb = CacheDb::<String, String>::with_buckets(1);
+        let evicted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        {
+            let evicted = Arc::clone(&evicted);
+            cdb.on_evict(move |key, value, cause| {
+                evicted.lock().unwrap().push((key, value, cause))
+            });
+        }
+
+        for key in ["foo", "bar", "baz", "ttl"] {
+            cdb.insert(&key.to_string(), |_| Ok(key.to_uppercase()))
+                .unwrap();
+        }
+        cdb.evict(1);
+        assert!(cdb.remove(&"bar".to_string()));
+
+        let baz = cdb.get(Blocking, &"baz".to_string()).unwrap();
+        assert!(cdb.remove(&"baz".to_string()));
+        assert_eq!(evicted.lock().unwrap().len(), 2);
+        drop(baz);
+
+        assert_eq!(
+            cdb.take(Blocking, &"ttl".to_string()),
+            Some("TTL".to_string())
+        );
+        cdb.get_or_insert_with_ttl(Blocking, &"ttl".to_string(), Duration::ZERO, |_| {
+            Ok("expired".to_string())
+        })
+        .unwrap();
+        assert!(!cdb.contains_key(&"ttl".to_string()));
+
+        assert_eq!(*evicted.lock().unwrap(), vec![
+            ("foo".to_string(), "FOO".to_string(), EvictionCause::Evicted),
+            ("bar".to_string(), "BAR".to_string(), EvictionCause::Removed),
+            ("baz".to_string(), "BAZ".to_string(), EvictionCause::Removed),
+            (
+                "ttl".to_string(),
+                "expired".to_string(),
+                EvictionCause::Expired
+            ),
+        ]);
+    }
+
+    #[test]
+    fn stats() {
+        init();
+        let cdb = IdentityDb::with_buckets(4);
+
+        for key in 0..8 {
+            cdb.insert(&key, |_| Ok(key)).unwrap();
+        }
+        assert!(cdb.insert(&8, |_| Err("failed".into())).is_err());
+        drop(cdb.get(Blocking, &0).unwrap());
+        assert!(cdb.get(Blocking, &9).is_err());
+        {
+            let _guard = cdb.get_mut(Blocking, &1).unwrap();
+            assert!(cdb.get(TryLock, &1).is_err());
+        }
+        cdb.evict(4);
+        cdb.get_or_insert_with_ttl(Blocking, &10, Duration::ZERO, |_| Ok(10))
+            .unwrap();
+        assert!(!cdb.contains_key(&10));
+
+        let stats = cdb.stats();
+        assert_eq!(stats.buckets.len(), 4);
+        assert_eq!(stats.total, BucketStats {
+            hits:          3,
+            misses:        11,
+            inserts:       9,
+            ctor_failures: 1,
+            evictions:     4,
+            expirations:   1,
+            lock_timeouts: 1,
+            len:           4,
+            cached:        4,
+            cache_target:  60,
+        });
+        // every key missed once, key 0 hit once and key 1 twice
+        let mut hits = vec![0; 4];
+        let mut misses = vec![0; 4];
+        hits[bucket_of(&cdb, 0)] += 1;
+        hits[bucket_of(&cdb, 1)] += 2;
+        for key in 0..=10 {
+            misses[bucket_of(&cdb, key)] += 1;
+        }
+        assert_eq!(
+            stats
+                .buckets
+                .iter()
+                .map(|stats| stats.hits)
+                .collect::<Vec<_>>(),
+            hits
+        );
+        assert_eq!(
+            stats
+                .buckets
+                .iter()
+                .map(|stats| stats.misses)
+                .collect::<Vec<_>>(),
+            misses
+        );
+        assert_eq!(stats.hit_ratio(), 3.0 / 14.0);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn prometheus_exposition() {
+        init();
+        let cdb = IdentityDb::with_buckets(2);
+        cdb.insert(&1, |_| Ok(1)).unwrap();
+        drop(cdb.get(Blocking, &1).unwrap());
+        let used = bucket_of(&cdb, 1);
+
+        let text = cdb.stats().to_prometheus("my \"cache\"");
+        assert!(text.contains("# TYPE cachedb_hits_total counter\n"));
+        assert!(text.contains(&format!(
+            "cachedb_hits_total{{cache=\"my \\\"cache\\\"\",bucket=\"{}\"}} 1\n",
+            used
+        )));
+        assert!(text.contains(&format!(
+            "cachedb_entries{{cache=\"my \\\"cache\\\"\",bucket=\"{}\"}} 0\n",
+            1 - used
+        )));
+        assert_eq!(text.lines().count(), 10 * 4);
+
+        // without a recorder installed this is a no-op
+        cdb.stats().record_metrics("cache");
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_locking() {
+        init();
+        let cdb = Arc::new(CacheDb::<u16, u16>::with_buckets(1));
+        cdb.insert(&1, |_| Ok(1)).unwrap();
+
+        fn assert_send<T: Send>(_: &T) {}
+        assert_send(&cdb.get_async(Blocking, &1));
+        assert_send(&cdb.get_or_insert_async(Blocking, &1, |_| async { Ok(1) }));
+
+        // hold the write lock in another thread
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let holder = {
+            let cdb = Arc::clone(&cdb);
+            thread::spawn(move || {
+                let mut guard = cdb.get_mut(Blocking, &1).unwrap();
+                locked_tx.send(()).unwrap();
+                thread::sleep(Duration::from_millis(100));
+                *guard = 2;
+            })
+        };
+        locked_rx.recv().unwrap();
+
+        assert!(matches!(
+            cdb.get_async(TryLock, &1).await,
+            Err(Error::LockUnavailable)
+        ));
+        assert!(matches!(
+            cdb.get_mut_async(Duration::from_millis(10), &1).await,
+            Err(Error::LockUnavailable)
+        ));
+        // dropping a waiting future releases the entry
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), cdb.get_async(Blocking, &1))
+                .await
+                .is_err()
+        );
+
+        assert_eq!(*cdb.get_async(Blocking, &1).await.unwrap(), 2);
+        assert_eq!(
+            *cdb.get_or_insert_async(Duration::from_secs(1), &1, |_| async { Ok(3) })
+                .await
+                .unwrap(),
+            2
+        );
+        holder.join().unwrap();
+        assert_eq!(cdb.stats().total.cached, 1);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn async_single_flight() {
+        init();
+        let cdb = Arc::new(CacheDb::<u16, u16>::with_buckets(1));
+        let calls = Arc::new(AtomicU32::new(0));
+
+        let tasks: Vec<_> = (0..10)
+            .map(|_| {
+                let cdb = Arc::clone(&cdb);
+                let calls = Arc::clone(&calls);
+                tokio::spawn(async move {
+                    let guard = cdb
+                        .get_or_insert_async(Blocking, &1, |key| {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            let value = *key + 1;
+                            async move {
+                                tokio::time::sleep(Duration::from_millis(50)).await;
+                                Ok(value)
+                            }
+                        })
+                        .await;
+                    *guard.unwrap()
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), 2);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn async_ctor_cancelled() {
+        init();
+        let cdb = Arc::new(CacheDb::<u16, u16>::with_buckets(1));
+
+        let constructing = {
+            let cdb = Arc::clone(&cdb);
+            tokio::spawn(async move {
+                let _ = cdb
+                    .get_or_insert_async(Blocking, &1, |_| std::future::pending())
+                    .await;
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(cdb.contains_key(&1));
+
+        let waiting = {
+            let cdb = Arc::clone(&cdb);
+            tokio::spawn(async move {
+                let guard = cdb
+                    .get_or_insert_async(Blocking, &1, |_| async { Ok(2) })
+                    .await;
+                *guard.unwrap()
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // the waiting task constructs the value instead
+        constructing.abort();
+        assert!(constructing.await.unwrap_err().is_cancelled());
+        assert_eq!(waiting.await.unwrap(), 2);
+        assert_eq!(cdb.stats().total.ctor_failures, 1);
+    }
+
+    // Evicts the most recently used entry first, only uses the public policy interface.
+    struct Mru<K, V> {
+        list: intrusive_collections::LinkedList<EntryAdapter<K, V>>,
+    }
+
+    impl<K, V> Default for Mru<K, V> {
+        fn default() -> Self {
+            Mru {
+                list: intrusive_collections::LinkedList::new(EntryAdapter::new()),
+            }
+        }
+    }
+
+    unsafe impl<K: KeyTraits, V> EvictionPolicy<K, V> for Mru<K, V> {
+        fn use_entry(&mut self, entry: &Entry<K, V>, cached: bool) {
+            if cached {
+                self.remove(entry);
+            }
+        }
+
+        fn release(&mut self, entry: UnsafeRef<Entry<K, V>>) {
+            self.list.push_front(entry);
+        }
+
+        fn remove(&mut self, entry: &Entry<K, V>) {
+            unsafe { self.list.cursor_mut_from_ptr(entry).remove() };
+        }
+
+        fn expire(&mut self, entry: &Entry<K, V>) {
+            self.remove(entry);
+            self.list.push_front(unsafe { UnsafeRef::from_raw(entry) });
+        }
+
+        fn victim(&mut self) -> Option<UnsafeRef<Entry<K, V>>> {
+            self.list.pop_front()
+        }
+
+        fn idle(
+            &mut self,
+            idle: &dyn Fn(&Entry<K, V>) -> bool,
+            _scan: usize,
+        ) -> Option<UnsafeRef<Entry<K, V>>> {
+            if self.list.back().get().is_some_and(idle) {
+                self.list.pop_back()
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn custom_policy() {
+        init();
+        let cdb = CacheDb::<String, String, Mru<String, String>>::with_buckets(1);
+
+        for key in ["foo", "bar", "baz"] {
+            cdb.insert(&key.to_string(), |_| Ok(key.to_string()))
+                .unwrap();
+        }
+        drop(cdb.get(Blocking, &"foo".to_string()).unwrap());
+
+        cdb.evict(1);
+        assert!(!cdb.contains_key(&"foo".to_string()));
+        cdb.evict(1);
+        assert!(!cdb.contains_key(&"baz".to_string()));
+        assert!(cdb.contains_key(&"bar".to_string()));
+    }
+
+    // Counts how often the hot keys had to be constructed while scans of keys that are used
+    // only once run in between. Hot keys are used twice per round.
+    fn hot_misses<P: EvictionPolicy<u16, u16>>() -> usize {
+        let cdb = CacheDb::<u16, u16, P>::with_buckets(1);
+        cdb.config_max_weight(100);
+
+        let mut misses = 0;
+        for round in 0..50 {
+            for key in (0..50).chain(0..50) {
+                cdb.get_or_insert(Blocking, &key, |_| {
+                    misses += 1;
+                    Ok(key)
+                })
+                .unwrap();
+            }
+            for key in 0..200 {
+                cdb.insert(&(1000 + round * 200 + key), |key| Ok(*key))
+                    .unwrap();
+            }
+        }
+        misses
+    }
+
+    #[test]
+    fn tinylfu_scan_resistance() {
+        init();
+        let lru = hot_misses::<Lru<u16, u16>>();
+        let tinylfu = hot_misses::<WTinyLfu<u16, u16>>();
+        assert_eq!(lru, 2500);
+        // W-TinyLFU needs a few rounds to learn which keys are hot
+        assert!(tinylfu < lru / 5);
+    }
+
+    #[test]
+    fn arc_scan_resistance() {
+        init();
+        let lru = hot_misses::<Lru<u16, u16>>();
+        let arc = hot_misses::<AdaptiveReplacement<u16, u16>>();
+        assert!(arc < lru / 5);
+    }
+
+    #[test]
+    pub fn multithreaded_stress_arc() {
+        stress::<AdaptiveReplacement<u16, u16>>();
+    }
+
     #[test]
     pub fn multithreaded_stress() {
+        stress::<Lru<u16, u16>>();
+    }
+
+    #[test]
+    pub fn multithreaded_stress_tinylfu() {
+        stress::<WTinyLfu<u16, u16>>();
+    }
+
+    fn stress<P>()
+    where
+        P: EvictionPolicy<u16, u16> + Send + 'static,
+    {
         const BUCKETS: usize = 64;
         init();
-        let cdb = Arc::new(CacheDb::<u16, u16, BUCKETS>::new());
+        let cdb = Arc::new(CacheDb::<u16, u16, P>::with_buckets(BUCKETS));
 
         let num_threads: usize = env::var("STRESS_THREADS")
             .unwrap_or("10".to_string())
@@ -763,7 +2693,7 @@ mod test {
                             c.wait();
 
                             let mut locked =
-                                HashMap::<u16, EntryReadGuard<u16, u16, BUCKETS>>::new();
+                                HashMap::<u16, EntryReadGuard<u16, u16, P>>::new();
                             let mut maxlocked: u16 = 0;
 
                             for _ in 0..iterations {
@@ -781,7 +2711,9 @@ mod test {
                                     // thread had no lock stored, create a new entry
                                     None => {
                                         if p < 15 {
-                                            // TODO: remove
+                                            #[cfg(feature = "logging")]
+                                            trace!("remove {}", r);
+                                            cdb.remove(&r);
                                         } else if p < 30 {
                                             // TODO: touch
                                         } else if p < 50 {
@@ -869,7 +2801,9 @@ mod test {
                                             trace!("unlock kept readguard {}", r);
                                             drop(read_guard);
                                         } else {
-                                            // TODO: drop-remove
+                                            #[cfg(feature = "logging")]
+                                            trace!("remove locked {} and unlock it", r);
+                                            cdb.remove(&r);
                                             drop(read_guard);
                                         }
                                     }
@@ -4,6 +4,8 @@
 
 pub use std::time::{Duration, Instant};
 
+use parking_lot::RwLockUpgradableReadGuard as Upgradable;
+
 use crate::Error;
 
 /// Marker for blocking locks,
@@ -23,8 +25,6 @@ pub struct Recursive<T>(pub T);
 // Tries to obtain the lock within a timeout.
 // Tries to obtain the lock until a target time expired.
 
-// PLANNED: Async<T>(pub T)
-
 /// Trait for implementing read/write flavors of locking methods.
 pub trait LockingMethod<'a, V> {
     // Obtain a read lock.
@@ -38,10 +38,25 @@ pub trait LockingMethod<'a, V> {
         &self,
         rwlock: &'a parking_lot::RwLock<Option<V>>,
     ) -> Result<parking_lot::RwLockWriteGuard<'a, Option<V>>, Error>;
+
+    // Obtain an upgradable read lock.
+    fn upgradable_read(
+        &self,
+        rwlock: &'a parking_lot::RwLock<Option<V>>,
+    ) -> Result<parking_lot::RwLockUpgradableReadGuard<'a, Option<V>>, Error>;
+
+    // Upgrade an upgradable read lock to a write lock, gives the guard back on failure.
+    fn upgrade(
+        &self,
+        guard: parking_lot::RwLockUpgradableReadGuard<'a, Option<V>>,
+    ) -> Result<
+        parking_lot::RwLockWriteGuard<'a, Option<V>>,
+        parking_lot::RwLockUpgradableReadGuard<'a, Option<V>>,
+    >;
 }
 
 macro_rules! impl_locking_method {
-    ($policy:ty, $read:expr, $write:expr) => {
+    ($policy:ty, $read:expr, $write:expr, $upgradable_read:expr, $upgrade:expr) => {
         impl<'a, V> LockingMethod<'a, V> for $policy {
             #[inline(always)]
             fn read(
@@ -80,16 +95,65 @@ macro_rules! impl_locking_method {
                 }
                 $write
             }
+
+            #[inline(always)]
+            fn upgradable_read(
+                &self,
+                rwlock: &'a parking_lot::RwLock<Option<V>>,
+            ) -> Result<parking_lot::RwLockUpgradableReadGuard<'a, Option<V>>, Error> {
+                #[allow(unused_macros)]
+                macro_rules! method {
+                    () => {
+                        self
+                    };
+                }
+                macro_rules! lock {
+                    () => {
+                        rwlock
+                    };
+                }
+                $upgradable_read
+            }
+
+            #[inline(always)]
+            fn upgrade(
+                &self,
+                guard: parking_lot::RwLockUpgradableReadGuard<'a, Option<V>>,
+            ) -> Result<
+                parking_lot::RwLockWriteGuard<'a, Option<V>>,
+                parking_lot::RwLockUpgradableReadGuard<'a, Option<V>>,
+            > {
+                #[allow(unused_macros)]
+                macro_rules! method {
+                    () => {
+                        self
+                    };
+                }
+                macro_rules! guard {
+                    () => {
+                        guard
+                    };
+                }
+                $upgrade
+            }
         }
     };
 }
 
-impl_locking_method!(Blocking, Ok(lock!().read()), Ok(lock!().write()));
+impl_locking_method!(
+    Blocking,
+    Ok(lock!().read()),
+    Ok(lock!().write()),
+    Ok(lock!().upgradable_read()),
+    Ok(Upgradable::upgrade(guard!()))
+);
 
 impl_locking_method!(
     TryLock,
     lock!().try_read().ok_or(Error::LockUnavailable),
-    lock!().try_write().ok_or(Error::LockUnavailable)
+    lock!().try_write().ok_or(Error::LockUnavailable),
+    lock!().try_upgradable_read().ok_or(Error::LockUnavailable),
+    Upgradable::try_upgrade(guard!())
 );
 
 impl_locking_method!(
@@ -99,7 +163,11 @@ impl_locking_method!(
         .ok_or(Error::LockUnavailable),
     lock!()
         .try_write_for(*method!())
-        .ok_or(Error::LockUnavailable)
+        .ok_or(Error::LockUnavailable),
+    lock!()
+        .try_upgradable_read_for(*method!())
+        .ok_or(Error::LockUnavailable),
+    Upgradable::try_upgrade_for(guard!(), *method!())
 );
 
 impl_locking_method!(
@@ -109,19 +177,28 @@ impl_locking_method!(
         .ok_or(Error::LockUnavailable),
     lock!()
         .try_write_until(*method!())
-        .ok_or(Error::LockUnavailable)
+        .ok_or(Error::LockUnavailable),
+    lock!()
+        .try_upgradable_read_until(*method!())
+        .ok_or(Error::LockUnavailable),
+    Upgradable::try_upgrade_until(guard!(), *method!())
 );
 
+// Upgradable locks are never recursive.
 impl_locking_method!(
     Recursive<Blocking>,
     Ok(lock!().read_recursive()),
-    Ok(lock!().write())
+    Ok(lock!().write()),
+    Ok(lock!().upgradable_read()),
+    Ok(Upgradable::upgrade(guard!()))
 );
 
 impl_locking_method!(
     Recursive<TryLock>,
     lock!().try_read_recursive().ok_or(Error::LockUnavailable),
-    lock!().try_write().ok_or(Error::LockUnavailable)
+    lock!().try_write().ok_or(Error::LockUnavailable),
+    lock!().try_upgradable_read().ok_or(Error::LockUnavailable),
+    Upgradable::try_upgrade(guard!())
 );
 
 impl_locking_method!(
@@ -131,7 +208,11 @@ impl_locking_method!(
         .ok_or(Error::LockUnavailable),
     lock!()
         .try_write_for(method!().0)
-        .ok_or(Error::LockUnavailable)
+        .ok_or(Error::LockUnavailable),
+    lock!()
+        .try_upgradable_read_for(method!().0)
+        .ok_or(Error::LockUnavailable),
+    Upgradable::try_upgrade_for(guard!(), method!().0)
 );
 
 impl_locking_method!(
@@ -141,5 +222,47 @@ impl_locking_method!(
         .ok_or(Error::LockUnavailable),
     lock!()
         .try_write_until(method!().0)
-        .ok_or(Error::LockUnavailable)
+        .ok_or(Error::LockUnavailable),
+    lock!()
+        .try_upgradable_read_until(method!().0)
+        .ok_or(Error::LockUnavailable),
+    Upgradable::try_upgrade_until(guard!(), method!().0)
 );
+
+/// Trait for the locking methods of the async API. Waiting for a lock suspends the task
+/// instead of blocking the thread. 'Blocking', 'TryLock', 'Duration' and 'Instant' are
+/// supported, recursive locking is not since tasks may move between threads.
+#[cfg(feature = "async")]
+pub trait AsyncLockingMethod {
+    /// The point in time until a lock is waited for, 'None' waits forever.
+    fn deadline(&self) -> Option<Instant>;
+}
+
+#[cfg(feature = "async")]
+impl AsyncLockingMethod for Blocking {
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncLockingMethod for TryLock {
+    fn deadline(&self) -> Option<Instant> {
+        Some(Instant::now())
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncLockingMethod for Duration {
+    fn deadline(&self) -> Option<Instant> {
+        // saturates to waiting forever
+        Instant::now().checked_add(*self)
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncLockingMethod for Instant {
+    fn deadline(&self) -> Option<Instant> {
+        Some(*self)
+    }
+}
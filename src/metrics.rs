@@ -0,0 +1,124 @@
+//! Exposition of the 'CacheStats' as metrics, enabled by the 'metrics' feature.
+use std::fmt::Write;
+
+use crate::{BucketStats, CacheStats};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+/// Name, kind, help text and value of every metric family.
+const FAMILIES: [(&str, Kind, &str, fn(&BucketStats) -> u64); 10] = [
+    (
+        "cachedb_hits_total",
+        Kind::Counter,
+        "Lookups that found an entry.",
+        |s| s.hits,
+    ),
+    (
+        "cachedb_misses_total",
+        Kind::Counter,
+        "Lookups that found no entry.",
+        |s| s.misses,
+    ),
+    (
+        "cachedb_inserts_total",
+        Kind::Counter,
+        "Entries constructed successfully.",
+        |s| s.inserts,
+    ),
+    (
+        "cachedb_ctor_failures_total",
+        Kind::Counter,
+        "Constructors that failed, panicked or were cancelled.",
+        |s| s.ctor_failures,
+    ),
+    (
+        "cachedb_evictions_total",
+        Kind::Counter,
+        "Entries evicted.",
+        |s| s.evictions,
+    ),
+    (
+        "cachedb_expirations_total",
+        Kind::Counter,
+        "Entries expired.",
+        |s| s.expirations,
+    ),
+    (
+        "cachedb_lock_timeouts_total",
+        Kind::Counter,
+        "Locking attempts that timed out.",
+        |s| s.lock_timeouts,
+    ),
+    ("cachedb_entries", Kind::Gauge, "Entries stored.", |s| {
+        s.len as u64
+    }),
+    (
+        "cachedb_cached_entries",
+        Kind::Gauge,
+        "Entries not in use.",
+        |s| s.cached as u64,
+    ),
+    (
+        "cachedb_cache_target_percent",
+        Kind::Gauge,
+        "Percentage of the capacity used for cached entries.",
+        |s| s.cache_target as u64,
+    ),
+];
+
+/// Escapes a label value for the Prometheus text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl CacheStats {
+    /// Renders the statistics of every bucket in the Prometheus text exposition format. The
+    /// series are labeled with the 'name' of the cache and the index of the bucket.
+    pub fn to_prometheus(&self, name: &str) -> String {
+        let name = escape(name);
+        let mut text = String::new();
+        for (metric, kind, help, value) in FAMILIES {
+            let kind = match kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+            };
+            // writing to a String never fails
+            let _ = writeln!(text, "# HELP {metric} {help}");
+            let _ = writeln!(text, "# TYPE {metric} {kind}");
+            for (index, bucket) in self.buckets.iter().enumerate() {
+                let _ = writeln!(
+                    text,
+                    "{metric}{{cache=\"{name}\",bucket=\"{index}\"}} {}",
+                    value(bucket)
+                );
+            }
+        }
+        text
+    }
+
+    /// Reports the statistics of every bucket to the recorder installed for the 'metrics'
+    /// facade. The metrics are labeled with the 'name' of the cache and the index of the
+    /// bucket. Should be called periodically, for example before the metrics are scraped.
+    pub fn record_metrics(&self, name: &str) {
+        for (metric, kind, help, value) in FAMILIES {
+            match kind {
+                Kind::Counter => ::metrics::describe_counter!(metric, help),
+                Kind::Gauge => ::metrics::describe_gauge!(metric, help),
+            }
+            for (index, bucket) in self.buckets.iter().enumerate() {
+                let labels = [("cache", name.to_string()), ("bucket", index.to_string())];
+                match kind {
+                    Kind::Counter => ::metrics::counter!(metric, &labels).absolute(value(bucket)),
+                    Kind::Gauge => ::metrics::gauge!(metric, &labels).set(value(bucket) as f64),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,562 @@
+use std::collections::{HashMap, VecDeque};
+
+use intrusive_collections::{LinkedList, UnsafeRef};
+
+use crate::entry::{Entry, EntryAdapter};
+use crate::KeyTraits;
+
+/// Decides which entries become evicted. Every bucket has its own policy instance which is
+/// protected by a mutex. Only unused entries are subject to eviction, these are handed to the
+/// policy by 'release()' and taken back by 'use_entry()', 'remove()', 'victim()' and 'idle()'.
+/// Policies usually keep them in intrusive lists linked through the entry ('EntryAdapter'),
+/// the 'segment' of an entry can be used to remember which list that is.
+///
+/// The sizing of the cache (how many entries are kept) is left to the bucket, the policy
+/// only selects the victims.
+///
+/// # Safety
+///
+/// The bucket drops the entries returned by 'victim()' and 'idle()'. Implementations must
+/// only return entries that were passed to 'release()' and not taken back since. Entries
+/// taken back must be unlinked from all intrusive lists of the policy.
+pub unsafe trait EvictionPolicy<K, V>: Default
+where
+    K: KeyTraits,
+{
+    /// A new entry got inserted into the map. It is in use while its value gets constructed
+    /// and passed to 'release()' later.
+    fn insert(&mut self, _entry: &Entry<K, V>) {}
+
+    /// An entry was looked up. When it was unused ('cached' is true) it has to be taken back.
+    fn use_entry(&mut self, entry: &Entry<K, V>, cached: bool);
+
+    /// The last user released an entry, it becomes a candidate for eviction.
+    fn release(&mut self, entry: UnsafeRef<Entry<K, V>>);
+
+    /// An unused entry got removed from the map, it has to be taken back.
+    fn remove(&mut self, entry: &Entry<K, V>);
+
+    /// An unused entry was marked for expiration and should be evicted next.
+    fn expire(&mut self, entry: &Entry<K, V>);
+
+    /// Takes back the entry that shall be evicted next. Returns 'None' when there are no
+    /// unused entries. Entries visited by 'CacheDb::for_each()' meanwhile are not evicted
+    /// but released again, only 'evicted()' tells that the entry is gone.
+    fn victim(&mut self) -> Option<UnsafeRef<Entry<K, V>>>;
+
+    /// An entry returned by 'victim()' got evicted.
+    fn evicted(&mut self, _entry: &Entry<K, V>) {}
+
+    /// Takes back an entry for which 'idle' returns true. This depends only on the time
+    /// entries were released, lists which are not strictly ordered by release time should be
+    /// checked for up to 'scan' entries from the front. Returns 'None' when there is no such
+    /// entry.
+    fn idle(
+        &mut self,
+        idle: &dyn Fn(&Entry<K, V>) -> bool,
+        scan: usize,
+    ) -> Option<UnsafeRef<Entry<K, V>>>;
+}
+
+/// Evicts the least recently used entry first. This is the default policy.
+pub struct Lru<K, V> {
+    list: LinkedList<EntryAdapter<K, V>>,
+}
+
+impl<K, V> Default for Lru<K, V> {
+    fn default() -> Self {
+        Lru {
+            list: LinkedList::new(EntryAdapter::new()),
+        }
+    }
+}
+
+unsafe impl<K, V> EvictionPolicy<K, V> for Lru<K, V>
+where
+    K: KeyTraits,
+{
+    fn use_entry(&mut self, entry: &Entry<K, V>, cached: bool) {
+        if cached {
+            self.remove(entry);
+        }
+    }
+
+    fn release(&mut self, entry: UnsafeRef<Entry<K, V>>) {
+        self.list.push_back(entry);
+    }
+
+    fn remove(&mut self, entry: &Entry<K, V>) {
+        unsafe { self.list.cursor_mut_from_ptr(entry).remove() };
+    }
+
+    fn expire(&mut self, entry: &Entry<K, V>) {
+        self.remove(entry);
+        self.list.push_front(unsafe { UnsafeRef::from_raw(entry) });
+    }
+
+    fn victim(&mut self) -> Option<UnsafeRef<Entry<K, V>>> {
+        self.list.pop_front()
+    }
+
+    fn idle(
+        &mut self,
+        idle: &dyn Fn(&Entry<K, V>) -> bool,
+        scan: usize,
+    ) -> Option<UnsafeRef<Entry<K, V>>> {
+        // ordered by release time, but entries marked for expiration are put at the front
+        take_idle(&mut self.list, idle, scan)
+    }
+}
+
+/// Takes the first entry of 'list' for which 'idle' returns true, checking at most 'scan'
+/// entries from the front.
+fn take_idle<K, V>(
+    list: &mut LinkedList<EntryAdapter<K, V>>,
+    idle: &dyn Fn(&Entry<K, V>) -> bool,
+    scan: usize,
+) -> Option<UnsafeRef<Entry<K, V>>> {
+    let mut cursor = list.front_mut();
+    for _ in 0..scan {
+        if idle(cursor.get()?) {
+            return cursor.remove();
+        }
+        cursor.move_next();
+    }
+    None
+}
+
+/// Counts how often keys were accessed recently in a count-min sketch of 4 bit counters. The
+/// counters are halved after ten times as many increments as keys are counted, thus old
+/// accesses fade away.
+struct FrequencySketch {
+    table:     Vec<u8>,
+    width:     usize,
+    capacity:  usize,
+    additions: usize,
+}
+
+impl FrequencySketch {
+    /// Counters per row for each counted key, keeps the error from collisions small.
+    const COUNTERS_PER_KEY: usize = 4;
+    const DEPTH: usize = 4;
+    const MAX_COUNT: u8 = 15;
+    const SEEDS: [u64; Self::DEPTH] = [
+        0xc3a5c85c97cb3127,
+        0xb492b66fbe98f273,
+        0x9ae16a3b2f90404f,
+        0xcbf29ce484222325,
+    ];
+
+    fn new() -> Self {
+        let mut sketch = FrequencySketch {
+            table:     Vec::new(),
+            width:     0,
+            capacity:  0,
+            additions: 0,
+        };
+        sketch.ensure_capacity(16);
+        sketch
+    }
+
+    /// Grows the sketch to count at least 'n' keys. The counts are lost when it grows.
+    fn ensure_capacity(&mut self, n: usize) {
+        if n > self.capacity {
+            self.capacity = n.next_power_of_two();
+            self.width = self.capacity * Self::COUNTERS_PER_KEY;
+            self.table = vec![0; Self::DEPTH * self.width];
+            self.additions = 0;
+        }
+    }
+
+    fn index(&self, hash: u64, row: usize) -> usize {
+        let hash = hash.wrapping_mul(Self::SEEDS[row]) >> 32;
+        row * self.width + (hash as usize & (self.width - 1))
+    }
+
+    fn increment(&mut self, hash: u64) {
+        let mut added = false;
+        for row in 0..Self::DEPTH {
+            let index = self.index(hash, row);
+            if self.table[index] < Self::MAX_COUNT {
+                self.table[index] += 1;
+                added = true;
+            }
+        }
+
+        if added {
+            self.additions += 1;
+            if self.additions >= 10 * self.capacity {
+                self.age();
+            }
+        }
+    }
+
+    fn frequency(&self, hash: u64) -> u8 {
+        (0..Self::DEPTH)
+            .map(|row| self.table[self.index(hash, row)])
+            .min()
+            .unwrap()
+    }
+
+    fn age(&mut self) {
+        for count in &mut self.table {
+            *count >>= 1;
+        }
+        self.additions /= 2;
+    }
+}
+
+/// Window-TinyLFU, resists scans and favors frequently used entries.
+///
+/// Released entries go into a small LRU admission window first. Entries overflowing the
+/// window are moved to the probation segment of the main region, entries that are used again
+/// while on probation become protected. Protected entries overflowing their share of the main
+/// region are demoted to probation again. When evicting, the entry that entered probation most
+/// recently competes against the least recently used one, the entry that was accessed less
+/// often according to a frequency sketch gets evicted.
+///
+/// Each segment is ordered by the time entries were put there, not by the time they were
+/// released. Idle entries are searched for up to 'evict_batch' entries deep, idle entries
+/// deeper in the main region are only discovered when they are looked up.
+pub struct WTinyLfu<K, V> {
+    segments: [LinkedList<EntryAdapter<K, V>>; 4],
+    lens:     [usize; 4],
+    sketch:   FrequencySketch,
+}
+
+impl<K, V> WTinyLfu<K, V> {
+    const EXPIRED: u8 = 3;
+    const PROBATION: u8 = 1;
+    const PROTECTED: u8 = 2;
+    /// Percent of the main region that is protected.
+    const PROTECTED_PERCENT: usize = 80;
+    const WINDOW: u8 = 0;
+    /// Percent of the cached entries in the admission window.
+    const WINDOW_PERCENT: usize = 1;
+
+    fn len(&self) -> usize {
+        self.lens.iter().sum()
+    }
+
+    fn link_back(&mut self, entry: UnsafeRef<Entry<K, V>>, segment: u8) {
+        entry.set_segment(segment);
+        self.lens[segment as usize] += 1;
+        self.segments[segment as usize].push_back(entry);
+    }
+
+    fn unlink(&mut self, entry: &Entry<K, V>) -> UnsafeRef<Entry<K, V>> {
+        let segment = entry.segment() as usize;
+        self.lens[segment] -= 1;
+        unsafe { self.segments[segment].cursor_mut_from_ptr(entry).remove() }.unwrap()
+    }
+
+    fn pop_front(&mut self, segment: u8) -> Option<UnsafeRef<Entry<K, V>>> {
+        let entry = self.segments[segment as usize].pop_front()?;
+        self.lens[segment as usize] -= 1;
+        Some(entry)
+    }
+
+    fn pop_back(&mut self, segment: u8) -> Option<UnsafeRef<Entry<K, V>>> {
+        let entry = self.segments[segment as usize].pop_back()?;
+        self.lens[segment as usize] -= 1;
+        Some(entry)
+    }
+
+    /// Moves the front of the 'from' segment to the back of the 'to' segment while 'from' has
+    /// more than 'max' entries.
+    fn overflow(&mut self, from: u8, to: u8, max: usize) {
+        while self.lens[from as usize] > max {
+            let entry = self.pop_front(from).unwrap();
+            self.link_back(entry, to);
+        }
+    }
+
+    fn frequency(&self, entry: &Entry<K, V>) -> u8 {
+        self.sketch.frequency(entry.hash())
+    }
+}
+
+impl<K, V> Default for WTinyLfu<K, V> {
+    fn default() -> Self {
+        WTinyLfu {
+            segments: [(); 4].map(|()| LinkedList::new(EntryAdapter::new())),
+            lens:     [0; 4],
+            sketch:   FrequencySketch::new(),
+        }
+    }
+}
+
+unsafe impl<K, V> EvictionPolicy<K, V> for WTinyLfu<K, V>
+where
+    K: KeyTraits,
+{
+    fn insert(&mut self, entry: &Entry<K, V>) {
+        self.sketch.increment(entry.hash());
+    }
+
+    fn use_entry(&mut self, entry: &Entry<K, V>, cached: bool) {
+        self.sketch.increment(entry.hash());
+        if cached {
+            self.unlink(entry);
+        }
+        match entry.segment() {
+            Self::PROBATION => entry.set_segment(Self::PROTECTED),
+            Self::EXPIRED => entry.set_segment(Self::WINDOW),
+            _ => {}
+        }
+    }
+
+    fn release(&mut self, entry: UnsafeRef<Entry<K, V>>) {
+        let segment = entry.segment();
+        self.link_back(entry, segment);
+
+        let len = self.len();
+        self.sketch.ensure_capacity(len);
+        match segment {
+            Self::WINDOW => {
+                let window_max = (len * Self::WINDOW_PERCENT / 100).max(1);
+                self.overflow(Self::WINDOW, Self::PROBATION, window_max);
+            }
+            Self::PROTECTED => {
+                let main =
+                    self.lens[Self::PROBATION as usize] + self.lens[Self::PROTECTED as usize];
+                let protected_max = (main * Self::PROTECTED_PERCENT / 100).max(1);
+                self.overflow(Self::PROTECTED, Self::PROBATION, protected_max);
+            }
+            _ => {}
+        }
+    }
+
+    fn remove(&mut self, entry: &Entry<K, V>) {
+        self.unlink(entry);
+    }
+
+    fn expire(&mut self, entry: &Entry<K, V>) {
+        let entry = self.unlink(entry);
+        self.link_back(entry, Self::EXPIRED);
+    }
+
+    fn victim(&mut self) -> Option<UnsafeRef<Entry<K, V>>> {
+        if let Some(entry) = self.pop_front(Self::EXPIRED) {
+            return Some(entry);
+        }
+
+        // The most recent entry on probation is the candidate for admission, it stays when
+        // it was accessed more often than the least recent one.
+        let probation = &self.segments[Self::PROBATION as usize];
+        if self.lens[Self::PROBATION as usize] > 1 {
+            let candidate = probation.back().get().unwrap();
+            let victim = probation.front().get().unwrap();
+            if self.frequency(candidate) <= self.frequency(victim) {
+                return self.pop_back(Self::PROBATION);
+            }
+        }
+
+        self.pop_front(Self::PROBATION)
+            .or_else(|| self.pop_front(Self::PROTECTED))
+            .or_else(|| self.pop_front(Self::WINDOW))
+    }
+
+    fn idle(
+        &mut self,
+        idle: &dyn Fn(&Entry<K, V>) -> bool,
+        scan: usize,
+    ) -> Option<UnsafeRef<Entry<K, V>>> {
+        for segment in [
+            Self::EXPIRED,
+            Self::WINDOW,
+            Self::PROBATION,
+            Self::PROTECTED,
+        ] {
+            if let Some(entry) = take_idle(&mut self.segments[segment as usize], idle, scan) {
+                self.lens[segment as usize] -= 1;
+                return Some(entry);
+            }
+        }
+        None
+    }
+}
+
+/// Keys of recently evicted entries, represented by their hashes. The oldest keys are dropped
+/// first.
+#[derive(Default)]
+struct GhostList {
+    // oldest first, contains stale items for keys that were removed or pushed again
+    order: VecDeque<(u64, u64)>,
+    // hash -> sequence number of its current item in 'order'
+    keys:  HashMap<u64, u64>,
+    next:  u64,
+}
+
+impl GhostList {
+    fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    fn push(&mut self, hash: u64) {
+        self.next += 1;
+        self.keys.insert(hash, self.next);
+        self.order.push_back((self.next, hash));
+        if self.order.len() > 2 * self.keys.len() + 16 {
+            let keys = &self.keys;
+            self.order.retain(|(seq, hash)| keys.get(hash) == Some(seq));
+        }
+    }
+
+    fn remove(&mut self, hash: u64) -> bool {
+        self.keys.remove(&hash).is_some()
+    }
+
+    fn pop_oldest(&mut self) {
+        while let Some((seq, hash)) = self.order.pop_front() {
+            if self.keys.get(&hash) == Some(&seq) {
+                self.keys.remove(&hash);
+                return;
+            }
+        }
+    }
+}
+
+/// Adaptive Replacement Cache, resists scans without tuning.
+///
+/// Entries that were used once are kept in a recency list, entries used again move into a
+/// frequency list. The keys of entries evicted from either list are remembered in ghost
+/// lists. When a key from a ghost list is inserted again, the target size of the recency list
+/// adapts towards the list it was evicted from, thus the split between recency and frequency
+/// follows the observed hits. The sizes are relative to the number of unused entries, since
+/// only these are managed by the policy.
+pub struct AdaptiveReplacement<K, V> {
+    segments: [LinkedList<EntryAdapter<K, V>>; 3],
+    lens:     [usize; 3],
+    // ghost lists of the recency and the frequency list
+    ghosts:   [GhostList; 2],
+    // target size of the recency list
+    target:   usize,
+}
+
+impl<K, V> AdaptiveReplacement<K, V> {
+    const EXPIRED: u8 = 2;
+    const FREQUENT: u8 = 1;
+    const RECENT: u8 = 0;
+
+    fn len(&self) -> usize {
+        self.lens.iter().sum()
+    }
+
+    fn unlink(&mut self, entry: &Entry<K, V>) {
+        let segment = entry.segment() as usize;
+        self.lens[segment] -= 1;
+        unsafe { self.segments[segment].cursor_mut_from_ptr(entry).remove() };
+    }
+
+    fn pop_front(&mut self, segment: u8) -> Option<UnsafeRef<Entry<K, V>>> {
+        let entry = self.segments[segment as usize].pop_front()?;
+        self.lens[segment as usize] -= 1;
+        Some(entry)
+    }
+}
+
+impl<K, V> Default for AdaptiveReplacement<K, V> {
+    fn default() -> Self {
+        AdaptiveReplacement {
+            segments: [(); 3].map(|()| LinkedList::new(EntryAdapter::new())),
+            lens:     [0; 3],
+            ghosts:   Default::default(),
+            target:   0,
+        }
+    }
+}
+
+unsafe impl<K, V> EvictionPolicy<K, V> for AdaptiveReplacement<K, V>
+where
+    K: KeyTraits,
+{
+    fn insert(&mut self, entry: &Entry<K, V>) {
+        let hash = entry.hash();
+        let [recent, frequent] = &mut self.ghosts;
+        let (recent_len, frequent_len) = (recent.len().max(1), frequent.len().max(1));
+
+        if recent.remove(hash) {
+            // evicted too early from the recency list
+            let cached = self.lens.iter().sum();
+            self.target = (self.target + (frequent_len / recent_len).max(1)).min(cached);
+            entry.set_segment(Self::FREQUENT);
+        } else if frequent.remove(hash) {
+            // evicted too early from the frequency list
+            self.target = self
+                .target
+                .saturating_sub((recent_len / frequent_len).max(1));
+            entry.set_segment(Self::FREQUENT);
+        }
+    }
+
+    fn use_entry(&mut self, entry: &Entry<K, V>, cached: bool) {
+        if cached {
+            self.unlink(entry);
+        }
+        entry.set_segment(Self::FREQUENT);
+    }
+
+    fn release(&mut self, entry: UnsafeRef<Entry<K, V>>) {
+        let segment = entry.segment() as usize;
+        self.lens[segment] += 1;
+        self.segments[segment].push_back(entry);
+    }
+
+    fn remove(&mut self, entry: &Entry<K, V>) {
+        self.unlink(entry);
+    }
+
+    fn expire(&mut self, entry: &Entry<K, V>) {
+        self.unlink(entry);
+        entry.set_segment(Self::EXPIRED);
+        self.lens[Self::EXPIRED as usize] += 1;
+        self.segments[Self::EXPIRED as usize].push_back(unsafe { UnsafeRef::from_raw(entry) });
+    }
+
+    fn victim(&mut self) -> Option<UnsafeRef<Entry<K, V>>> {
+        if let Some(entry) = self.pop_front(Self::EXPIRED) {
+            return Some(entry);
+        }
+
+        let recent = self.lens[Self::RECENT as usize];
+        if recent > 0 && (recent > self.target || self.lens[Self::FREQUENT as usize] == 0) {
+            self.pop_front(Self::RECENT)
+        } else {
+            self.pop_front(Self::FREQUENT)
+        }
+    }
+
+    fn evicted(&mut self, entry: &Entry<K, V>) {
+        // Remember the key in the ghost list of its segment, the ghost lists are trimmed to
+        // the number of cached entries.
+        let segment = entry.segment();
+        if segment == Self::EXPIRED {
+            return;
+        }
+        self.ghosts[segment as usize].push(entry.hash());
+
+        let cached = self.len().max(1);
+        let [recent, frequent] = &mut self.ghosts;
+        while self.lens[Self::RECENT as usize] + recent.len() > cached && recent.len() > 0 {
+            recent.pop_oldest();
+        }
+        while cached + recent.len() + frequent.len() > 2 * cached && frequent.len() > 0 {
+            frequent.pop_oldest();
+        }
+    }
+
+    fn idle(
+        &mut self,
+        idle: &dyn Fn(&Entry<K, V>) -> bool,
+        scan: usize,
+    ) -> Option<UnsafeRef<Entry<K, V>>> {
+        for segment in [Self::EXPIRED, Self::RECENT, Self::FREQUENT] {
+            if let Some(entry) = take_idle(&mut self.segments[segment as usize], idle, scan) {
+                self.lens[segment as usize] -= 1;
+                return Some(entry);
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,70 @@
+/// Statistics of a single bucket. The counters are cumulative since the CacheDb was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BucketStats {
+    /// Lookups that found an entry.
+    pub hits:          u64,
+    /// Lookups that found no entry.
+    pub misses:        u64,
+    /// Entries that were constructed successfully.
+    pub inserts:       u64,
+    /// Constructors that failed, panicked or were cancelled.
+    pub ctor_failures: u64,
+    /// Entries evicted by the eviction policy or because the 'max_weight' was exceeded.
+    pub evictions:     u64,
+    /// Entries removed because their time to live ended or they were idle for too long.
+    pub expirations:   u64,
+    /// Locking attempts that failed with 'Error::LockUnavailable'.
+    pub lock_timeouts: u64,
+    /// The number of entries stored.
+    pub len:           usize,
+    /// The number of entries not in use, which are subject to eviction.
+    pub cached:        usize,
+    /// The percentage of the capacity the bucket aims to use for cached entries.
+    pub cache_target:  u8,
+}
+
+/// Statistics of a CacheDb, returned by 'CacheDb::stats()'. The counters are collected bucket
+/// by bucket without stopping concurrent operations, thus they are not a consistent snapshot.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The sum over all buckets. The 'cache_target' is the average of the buckets.
+    pub total:   BucketStats,
+    /// The statistics of each bucket.
+    pub buckets: Vec<BucketStats>,
+}
+
+impl CacheStats {
+    pub(crate) fn new(buckets: Vec<BucketStats>) -> Self {
+        let mut total = buckets
+            .iter()
+            .fold(BucketStats::default(), |total, bucket| BucketStats {
+                hits:          total.hits + bucket.hits,
+                misses:        total.misses + bucket.misses,
+                inserts:       total.inserts + bucket.inserts,
+                ctor_failures: total.ctor_failures + bucket.ctor_failures,
+                evictions:     total.evictions + bucket.evictions,
+                expirations:   total.expirations + bucket.expirations,
+                lock_timeouts: total.lock_timeouts + bucket.lock_timeouts,
+                len:           total.len + bucket.len,
+                cached:        total.cached + bucket.cached,
+                cache_target:  0,
+            });
+        total.cache_target = (buckets
+            .iter()
+            .map(|bucket| bucket.cache_target as usize)
+            .sum::<usize>()
+            .checked_div(buckets.len())
+            .unwrap_or(0)) as u8;
+        CacheStats { total, buckets }
+    }
+
+    /// The fraction of lookups that found an entry, 0.0 when nothing was looked up yet.
+    pub fn hit_ratio(&self) -> f64 {
+        let lookups = self.total.hits + self.total.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.total.hits as f64 / lookups as f64
+        }
+    }
+}